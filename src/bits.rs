@@ -0,0 +1,169 @@
+use crate::wire::DecodeError;
+
+/// Writes values using only as many bits as they need.
+/// Bits are filled from the least significant end of each byte.
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    // Bits waiting to be flushed out to bytes
+    scratch: u64,
+    scratch_bits: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Write the lowest `bits` bits of value, up to 32 at a time
+    pub fn write_bits(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits <= 32);
+        if bits == 0 {
+            return;
+        }
+
+        let mask = if bits == 32 { u32::MAX } else { (1 << bits) - 1 };
+        self.scratch |= ((value & mask) as u64) << self.scratch_bits;
+        self.scratch_bits += bits;
+
+        while self.scratch_bits >= 8 {
+            self.bytes.push(self.scratch as u8);
+            self.scratch >>= 8;
+            self.scratch_bits -= 8;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u32, 1);
+    }
+
+    // 7 bits at a time with a continuation bit, so small numbers stay small
+    pub fn write_varint(&mut self, mut value: u32) {
+        loop {
+            let group = value & 0x7f;
+            value >>= 7;
+            self.write_bits(group, 7);
+            self.write_bool(value != 0);
+            if value == 0 {
+                break;
+            }
+        }
+    }
+
+    // Zigzag encode first so small negative numbers stay small too
+    pub fn write_signed_varint(&mut self, value: i32) {
+        self.write_varint(((value << 1) ^ (value >> 31)) as u32);
+    }
+
+    // Number of bits written so far
+    pub fn bit_len(&self) -> usize {
+        self.bytes.len() * 8 + self.scratch_bits as usize
+    }
+
+    // Pads the last byte with zeroes
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.scratch_bits > 0 {
+            self.bytes.push(self.scratch as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Reads back values written by a `BitWriter`
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    // Position in bits from the start of bytes
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    pub fn read_bits(&mut self, bits: u32) -> Result<u32, DecodeError> {
+        debug_assert!(bits <= 32);
+        if self.position + bits as usize > self.bytes.len() * 8 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+
+        let mut value = 0u32;
+        let mut read = 0;
+        while read < bits {
+            let byte = self.bytes[self.position / 8];
+            let offset = (self.position % 8) as u32;
+            // Take as many bits as we can from the current byte
+            let count = (8 - offset).min(bits - read);
+            let chunk = (byte as u32 >> offset) & ((1 << count) - 1);
+
+            value |= chunk << read;
+            read += count;
+            self.position += count as usize;
+        }
+
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, DecodeError> {
+        Ok(self.read_bits(1)? != 0)
+    }
+
+    pub fn read_varint(&mut self) -> Result<u32, DecodeError> {
+        let mut value = 0u32;
+        let mut shift = 0;
+        loop {
+            let group = self.read_bits(7)?;
+            // 5 groups covers 32 bits, anything longer is garbage
+            if shift > 28 || (shift == 28 && group > 0xf) {
+                return Err(DecodeError::InvalidVarint);
+            }
+            value |= group << shift;
+            shift += 7;
+
+            if !self.read_bool()? {
+                return Ok(value);
+            }
+        }
+    }
+
+    pub fn read_signed_varint(&mut self) -> Result<i32, DecodeError> {
+        let value = self.read_varint()?;
+        Ok((value >> 1) as i32 ^ -((value & 1) as i32))
+    }
+
+    // Whole bytes that haven't been touched yet, the padding in a
+    // partially read byte doesn't count
+    pub fn remaining_bytes(&self) -> usize {
+        self.bytes.len() - self.position.div_ceil(8)
+    }
+}
+
+/// Maps a float in `min..=max` onto an integer in steps of `precision`
+#[derive(Debug, Clone, Copy)]
+pub struct Quantization {
+    pub min: f32,
+    pub max: f32,
+    pub precision: f32,
+}
+
+impl Quantization {
+    // Largest quantized value
+    fn steps(&self) -> u32 {
+        ((self.max - self.min) / self.precision).ceil() as u32
+    }
+
+    // Bits needed to hold any quantized value
+    pub fn bits(&self) -> u32 {
+        32 - self.steps().leading_zeros()
+    }
+
+    // Values outside of the range are clamped to it
+    pub fn quantize(&self, value: f32) -> u32 {
+        let value = value.clamp(self.min, self.max);
+        (((value - self.min) / self.precision).round() as u32).min(self.steps())
+    }
+
+    pub fn dequantize(&self, value: u32) -> f32 {
+        self.min + value as f32 * self.precision
+    }
+}
@@ -7,32 +7,102 @@ use std::{
 use macroquad::input::{is_key_down, KeyCode};
 
 use crate::{
-    net::{Message, State, UnreliableNetwork},
+    clock::{SharedClock, SystemClock},
+    clocksync::ClockSync,
+    combat::{self, CombatEvent, Tracer},
+    connection::Connection,
+    handshake::{self, ConnectionState, DenyReason, Handshake},
+    net::{LinkConditions, Message, SharedTransport, State, UnreliableNetwork},
+    rng::SimRng,
     server::Server,
-    sim::{Colour, Entity, Input, World},
+    sim::{Colour, Entity, Input, World, MAX_HEALTH},
     ticktimer::TickTimer,
 };
 
+// How many snapshots from the server we keep to apply deltas to
+const SNAPSHOT_BASELINE_LENGTH: usize = 64;
+
+// How many kills are kept for showing
+const KILL_FEED_LENGTH: usize = 5;
+
+// How far behind the target tick we can fall before jumping forward to it
+const MAX_TICK_DRIFT: i32 = 2;
+
+// Most the tick interval is stretched or squeezed to hold the input buffer,
+// and how much per tick the buffer is off by
+const MAX_TICK_DILATION: f64 = 0.05;
+const TICK_DILATION_PER_TICK: f64 = 0.02;
+// How many times the jitter in our input lead we add on top of the minimum,
+// so input still arrives in time when it's slower than usual
+const INPUT_LEAD_JITTER_MARGIN: f32 = 2.0;
+
 /// Represents networked client
 pub struct Client {
     id: i32,
 
+    // Time for the tick timer and connection
+    clock: SharedClock,
+
     // Timer for fixed tickrate
     pub tick_timer: TickTimer,
 
+    // Estimate of the server's tick, so we can run ahead of it
+    pub clock_sync: ClockSync,
+    // Whether our ticks have been lined up with the server's yet
+    tick_synced: bool,
+
+    // How many ticks early we want input to reach the server, to ride out jitter.
+    // Never less than min_input_lead, and more the more the lead jumps around
+    pub target_input_lead: f32,
+    pub min_input_lead: f32,
+    // Smoothed lead the server has been seeing on our input, and how far
+    // each report tends to be from it
+    pub input_lead: Option<f32>,
+    pub input_lead_jitter: f32,
+
     // The tick rate in milliseconds
     pub tick_rate_ms: u64,
 
     // Network interface for sending and receiving messages to this client
     // The RC/RefCell is for mutable borrowing to the client network
-    pub network: Rc<RefCell<UnreliableNetwork>>,
-    // The RC/RefCell is for mutable borrowing to the server network
-    server_network: Option<Rc<RefCell<UnreliableNetwork>>>,
+    pub network: SharedTransport,
+    // The server's inbox, for handshaking before there's a connection
+    server_network: Option<SharedTransport>,
+    // Connection to the server, wrapping the server network
+    connection: Option<Connection>,
+    pub state: ConnectionState,
+    // Given to us by the server when we connect, for carrying on where we left
+    // off if we drop and connect again
+    session: Option<u32>,
+    // Picked fresh each time we start connecting, so the server can tell our
+    // new attempt from a late copy of an old request
+    connect_nonce: u32,
+    // For picking nonces
+    rng: SimRng,
+    // When we last sent the handshake message for the state we're in
+    last_handshake_sent: Option<std::time::Duration>,
+    // When we started connecting or last got a step further, for giving up
+    // on a server that stops answering part way through
+    connecting_since: std::time::Duration,
+    // Hearing nothing from the server for this long counts as interrupted,
+    // and for timeout we give up on it, connected or still connecting
+    pub interrupted_after: std::time::Duration,
+    pub timeout: std::time::Duration,
 
     // Client simulation data
     pub world: World,
+    // Where each entity was before the latest tick, for drawing between ticks
+    previous_positions: HashMap<i32, (f32, f32)>,
 
     networked_entities: HashMap<i32, i32>,
+    // Server entities we've been told are gone, so a late snapshot doesn't bring
+    // them back. Along with the newest snapshot tick we had when told
+    despawned_entities: HashMap<i32, i32>,
+    // Newest snapshot tick each of our entities was in
+    entity_last_seen: HashMap<i32, i32>,
+    // Entities missing from snapshots for this many ticks are removed,
+    // in case we never hear they were despawned
+    pub entity_timeout_ticks: i32,
 
     // The entity that the client controls
     controlled_entity: Option<i32>,
@@ -45,6 +115,14 @@ pub struct Client {
     // We store the processed sequence(tick) and the input
     pub input_history: VecDeque<(i32, Input)>,
 
+    // Inputs the server hasn't told us it processed, resent with each new input
+    unacked_inputs: VecDeque<(i32, Input)>,
+    // How many earlier inputs go along with each new one
+    pub redundant_inputs: usize,
+    // Send input every tick even with nothing pressed, so the server can tell
+    // no input from lost input and input sequences have no gaps
+    pub send_idle_input: bool,
+
     pub last_message_sequence: i32,
 
     pub client_prediction_enabled: bool,
@@ -53,35 +131,96 @@ pub struct Client {
     pub extrapolation_enabled: bool,
 
     // Stores the state snapshots from the server for use with extrapolation
+    // We store the server tick of the snapshot and the state
     pub state_snapshots: HashMap<i32, VecDeque<(i32, State)>>,
 
+    // How many ticks behind the newest snapshot other entities are drawn, so
+    // a late or lost snapshot doesn't leave nothing to blend towards
+    pub interpolation_delay_ticks: f32,
+    // Server tick other entities were last drawn at, sent with input so the
+    // server can check actions against what we saw
+    view_tick: Option<f32>,
+
+    // Full world state of recent server snapshots by tick, for rebuilding deltas
+    snapshot_baselines: VecDeque<(i32, HashMap<i32, State>)>,
+
+    // Newest snapshot tick we have received and the last one we told the server about
+    latest_snapshot_tick: Option<i32>,
+    acked_snapshot_tick: Option<i32>,
+
     pub use_alternate_input: bool,
+    // Read input from the keyboard every update, turn off to feed it in with set_input
+    pub keyboard_input_enabled: bool,
     pub colour: Colour,
 
-    pub connected: bool,
+    // Hitscan shots still being drawn
+    pub tracers: Vec<Tracer>,
+    // Recent kills as our own entity ids, shooter then target, newest last
+    pub kill_feed: VecDeque<(i32, i32)>,
 }
 
 impl Client {
     pub fn new(id: i32, tick_rate_ms: u64) -> Self {
+        Self::with_network(id, tick_rate_ms, Rc::new(RefCell::new(UnreliableNetwork::new())))
+    }
+
+    // Create a client that receives on the given transport
+    pub fn with_network(id: i32, tick_rate_ms: u64, network: SharedTransport) -> Self {
+        Self::with_rng_and_clock(id, tick_rate_ms, network, SimRng::from_time(), Rc::new(SystemClock::new()))
+    }
+
+    // Create a client whose ticks and connection run off the given clock.
+    // Same rng seed and clock picks the same connect nonces every run
+    pub fn with_rng_and_clock(id: i32, tick_rate_ms: u64, network: SharedTransport, rng: SimRng, clock: SharedClock) -> Self {
         Client {
             id,
-            tick_timer: TickTimer::new(std::time::Duration::from_millis(tick_rate_ms)),
+            tick_timer: TickTimer::with_clock(std::time::Duration::from_millis(tick_rate_ms), Rc::clone(&clock)),
+            clock,
+            clock_sync: ClockSync::new(),
+            tick_synced: false,
+            target_input_lead: 1.0,
+            min_input_lead: 1.0,
+            input_lead: None,
+            input_lead_jitter: 0.0,
             tick_rate_ms,
-            network: Rc::new(RefCell::new(UnreliableNetwork::new())),
+            network,
             server_network: None,
+            connection: None,
+            state: ConnectionState::Disconnected,
+            session: None,
+            connect_nonce: 0,
+            rng,
+            last_handshake_sent: None,
+            connecting_since: std::time::Duration::ZERO,
+            interrupted_after: std::time::Duration::from_millis(500),
+            timeout: std::time::Duration::from_secs(3),
             world: World::new(),
+            previous_positions: HashMap::new(),
             networked_entities: HashMap::new(),
+            despawned_entities: HashMap::new(),
+            entity_last_seen: HashMap::new(),
+            entity_timeout_ticks: 40,
             controlled_entity: None,
             input_state: None,
             input_history: VecDeque::new(),
+            unacked_inputs: VecDeque::new(),
+            redundant_inputs: 4,
+            send_idle_input: true,
             last_message_sequence: 0,
             client_prediction_enabled: true,
             server_reconciliation_enabled: true,
             extrapolation_enabled: true,
             state_snapshots: HashMap::new(),
+            interpolation_delay_ticks: 2.0,
+            view_tick: None,
+            snapshot_baselines: VecDeque::new(),
+            latest_snapshot_tick: None,
+            acked_snapshot_tick: None,
             use_alternate_input: false,
+            keyboard_input_enabled: true,
             colour: Colour::Red,
-            connected: false,
+            tracers: Vec::new(),
+            kill_feed: VecDeque::new(),
         }
     }
 
@@ -89,43 +228,215 @@ impl Client {
         self.id
     }
 
-    pub fn get_network(&self) -> Rc<RefCell<UnreliableNetwork>> {
+    pub fn get_network(&self) -> SharedTransport {
         Rc::clone(&self.network)
     }
 
-    // This is a function to fake connections on our fake network
-    // up the network connection.
-    // In the real world this would happen via network messages.
-    // The client version sets its own controlled entity
-    pub fn connect(
-        &mut self,
-        server: &mut Server,
-        min_latency_ms: u64,
-        max_latency_ms: u64,
-        drop_rate: f32,
-    ) {
-        let server_player_entity_id = server.connect(self);
+    // Connect to a server on our fake network. It has no addresses, so the
+    // server is told where to reach us, then we handshake like we would over UDP
+    pub fn connect(&mut self, server: &mut Server, upstream: LinkConditions, downstream: LinkConditions) {
+        server.add_route(self.id, self.get_network());
         let server_network = server.get_network();
 
-        // Set the same latency for both client and server
-        server_network.borrow_mut().min_latency_ms = min_latency_ms;
-        server_network.borrow_mut().max_latency_ms = max_latency_ms;
-        server_network.borrow_mut().drop_rate = drop_rate;
+        // Each direction is its own link. What we send only goes through our
+        // link into the server inbox, other clients have their own
+        server_network.borrow_mut().set_sender_conditions(self.id, upstream);
+        self.network.borrow_mut().set_conditions(downstream);
 
-        self.network.borrow_mut().min_latency_ms = min_latency_ms;
-        self.network.borrow_mut().max_latency_ms = max_latency_ms;
-        self.network.borrow_mut().drop_rate = drop_rate;
+        self.connect_to(server_network);
+    }
+
+    // Start connecting to a server we can reach through the given transport.
+    // Any connection we already have is dropped without telling the server,
+    // so if it's the same one it keeps our player for us to carry on with
+    pub fn connect_to(&mut self, server_network: SharedTransport) {
+        self.reset();
+
+        // Throw away anything left over from before, like the rest of the denies
+        // from a last attempt, so it isn't taken as an answer to this one
+        {
+            let mut network = self.network.borrow_mut();
+            network.poll();
+            while network.receive().is_some() {}
+        }
 
-        // Store the server network for sending messages to the server
         self.server_network = Some(server_network);
+        self.connect_nonce = (self.rng.next_u64() >> 32) as u32;
+        self.request_connection();
+    }
+
+    // Ask the server to let us in, from the start of the handshake
+    fn request_connection(&mut self) {
+        self.state = ConnectionState::Requesting;
+        self.connecting_since = self.clock.now();
+        self.last_handshake_sent = None;
+    }
+
+    // Tell the server we're leaving for good and forget everything we had from it
+    pub fn disconnect(&mut self) {
+        if self.is_connected() || self.is_connecting() {
+            // Nobody is waiting around to resend it, so send a few in case some are lost
+            for _ in 0..handshake::DISCONNECT_REDUNDANCY {
+                self.send_handshake(Handshake::Disconnect);
+            }
+        }
+
+        self.reset();
+        self.session = None;
+    }
+
+    // The session we'll ask to carry on with next time we connect
+    pub fn session(&self) -> Option<u32> {
+        self.session
+    }
+
+    // Interrupted still counts, we carry on as normal hoping the server comes back
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, ConnectionState::Connected | ConnectionState::Interrupted)
+    }
+
+    pub fn is_connecting(&self) -> bool {
+        matches!(self.state, ConnectionState::Requesting | ConnectionState::Challenged { .. })
+    }
+
+    // Back to how we were before connecting, apart from our session
+    fn reset(&mut self) {
+        self.state = ConnectionState::Disconnected;
+        self.server_network = None;
+        self.connection = None;
+        self.last_handshake_sent = None;
+
+        self.world = World::new();
+        self.previous_positions.clear();
+        self.networked_entities.clear();
+        self.despawned_entities.clear();
+        self.entity_last_seen.clear();
+        self.controlled_entity = None;
+        self.input_state = None;
+        self.input_history.clear();
+        self.unacked_inputs.clear();
+        self.last_message_sequence = 0;
+        self.state_snapshots.clear();
+        self.view_tick = None;
+        self.snapshot_baselines.clear();
+        self.latest_snapshot_tick = None;
+        self.acked_snapshot_tick = None;
+        self.tracers.clear();
+        self.kill_feed.clear();
+
+        // The next server might tick differently
+        self.clock_sync.reset();
+        self.tick_synced = false;
+        self.input_lead = None;
+        self.input_lead_jitter = 0.0;
+        self.target_input_lead = self.min_input_lead;
+        self.tick_timer.dilation = 1.0;
+    }
+
+    // Send a handshake message straight to the server, outside of any connection
+    fn send_handshake(&self, handshake: Handshake) {
+        if let Some(server_network) = &self.server_network {
+            server_network.borrow_mut().send(
+                self.id,
+                Message {
+                    handshake: Some(handshake),
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    // Read handshake replies and keep asking until we're connected
+    fn update_handshake(&mut self) {
+        // Our own handle so the client can be borrowed while receiving
+        let network = Rc::clone(&self.network);
+        let mut network = network.borrow_mut();
+        while let Some((_sender_id, message)) = network.receive() {
+            // Anything else is from a connection we don't have yet
+            if let Some(handshake) = message.handshake {
+                self.handle_handshake(handshake);
+            }
+        }
+
+        let now = self.clock.now();
+        if self.is_connecting() && now.saturating_sub(self.connecting_since) >= self.timeout {
+            self.reset();
+            self.state = ConnectionState::TimedOut;
+            return;
+        }
+
+        let handshake = match self.state {
+            ConnectionState::Requesting => Handshake::ConnectRequest {
+                colour: self.colour,
+                session: self.session,
+                nonce: self.connect_nonce,
+            },
+            ConnectionState::Challenged { salt } => Handshake::ChallengeResponse { salt },
+            _ => return,
+        };
+
+        let due = self
+            .last_handshake_sent
+            .is_none_or(|last_sent| now.saturating_sub(last_sent) >= handshake::HANDSHAKE_RESEND_INTERVAL);
+        if due {
+            self.send_handshake(handshake);
+            self.last_handshake_sent = Some(now);
+        }
+    }
+
+    fn handle_handshake(&mut self, handshake: Handshake) {
+        let connecting = self.is_connecting();
+
+        match handshake {
+            // Only for this attempt, not one that was still on its way from the last
+            Handshake::Challenge { salt, nonce }
+                if self.state == ConnectionState::Requesting && nonce == self.connect_nonce =>
+            {
+                // Answer straight away
+                self.state = ConnectionState::Challenged { salt };
+                self.connecting_since = self.clock.now();
+                self.last_handshake_sent = None;
+            }
+            Handshake::Accept { entity_id, session } if connecting => {
+                self.session = Some(session);
+                self.accept(entity_id);
+            }
+            // The server forgot our challenge, ask for a new one. Any other time
+            // it's about a challenge from before the one we're on
+            Handshake::Deny {
+                reason: DenyReason::ChallengeExpired,
+            } => {
+                if matches!(self.state, ConnectionState::Challenged { .. }) {
+                    self.request_connection();
+                }
+            }
+            Handshake::Deny { reason } if connecting => {
+                self.reset();
+                self.state = ConnectionState::Denied(reason);
+            }
+            // Only while connected, one left over from an old connection
+            // shouldn't stop us making a new one
+            Handshake::Disconnect if self.is_connected() => {
+                self.reset();
+            }
+            // Repeats of things we've already dealt with
+            _ => {}
+        }
+    }
+
+    // The server let us in, controlling the server entity it assigned us
+    fn accept(&mut self, server_player_entity_id: i32) {
+        let Some(server_network) = self.server_network.clone() else {
+            return;
+        };
+        self.connection = Some(Connection::with_clock(self.id, server_network, Rc::clone(&self.clock)));
 
-        // Set controlled entity to the entity we got from the server
-        // As in server this probably would have happened over RPC assignment
         // Create local entity for player
         let entity = Entity {
             position: (0., 0.),
             speed: 5.0,
             colour: self.colour,
+            ..Entity::new()
         };
 
         let client_player_entity_id = self.world.add_entity(entity);
@@ -133,240 +444,742 @@ impl Client {
         self.networked_entities.insert(server_player_entity_id, client_player_entity_id);
         self.controlled_entity = Some(client_player_entity_id);
 
-        self.connected = true;
+        self.state = ConnectionState::Connected;
     }
 
     pub fn update(&mut self) {
-        if !self.connected {
+        if !self.is_connected() && !self.is_connecting() {
             return;
         }
 
-        self.get_input();
+        if self.keyboard_input_enabled {
+            self.get_input();
+        }
+
+        self.network.borrow_mut().poll();
+
+        if !self.is_connected() {
+            self.update_handshake();
+            if !self.is_connected() {
+                return;
+            }
+        }
+
+        self.sync_tick();
 
         // Fixed tickrate
         for tick in self.tick_timer.tick() {
             //println!("Client tick: {}", tick);
+            self.previous_positions = self
+                .world
+                .get_entities()
+                .iter()
+                .map(|(entity_id, entity)| (*entity_id, entity.position))
+                .collect();
+
             // Listen to the server and process server messages
-            self.process_server_messages(tick);
+            self.process_server_messages();
+
+            // The server told us to leave
+            if !self.is_connected() {
+                break;
+            }
+
+            self.update_combat();
 
             // Interpolate entities
             if self.extrapolation_enabled {
-                self.interpolate_entities(tick);
+                self.interpolate_entities();
             }
 
             // Process input and send it to the server
-            self.process_input();
+            self.process_input(tick);
         }
+
+        self.check_connection();
     }
 
-    fn process_server_messages(&mut self, tick: i32) {
-        let mut network = self.network.borrow_mut();
-        while let Some((_sender_id, message)) = network.receive() {
-            // If message sequence is less than the last processed message
-            // we ignore it as it's out of sequence and therefore old
-            if message.sequence < self.last_message_sequence {
+    // Entities as they should be drawn this frame, blended from where they were
+    // before the latest tick to where it left them by how far into the next
+    // tick we are. Anything new that tick is drawn where it is
+    pub fn render_entities(&self) -> Vec<Entity> {
+        let alpha = self.tick_timer.alpha().min(1.0);
+        self.world
+            .get_entities()
+            .iter()
+            .map(|(entity_id, entity)| {
+                let mut entity = *entity;
+                if let Some(previous) = self.previous_positions.get(entity_id) {
+                    entity.position = (
+                        previous.0 + (entity.position.0 - previous.0) * alpha,
+                        previous.1 + (entity.position.1 - previous.1) * alpha,
+                    );
+                }
+                entity
+            })
+            .collect()
+    }
+
+    // Notice the server going quiet, and give up on it if it stays that way
+    fn check_connection(&mut self) {
+        if !self.is_connected() {
+            return;
+        }
+        let Some(connection) = &self.connection else {
+            return;
+        };
+
+        let silent_for = connection.time_since_received();
+        if silent_for >= self.timeout {
+            self.reset();
+            self.state = ConnectionState::TimedOut;
+        } else if silent_for >= self.interrupted_after {
+            self.state = ConnectionState::Interrupted;
+        } else {
+            self.state = ConnectionState::Connected;
+        }
+    }
+
+    fn process_server_messages(&mut self) {
+        // Our own handle so the client can be borrowed while receiving
+        let network = Rc::clone(&self.network);
+        let mut network = network.borrow_mut();
+        while let Some((_sender_id, packet)) = network.receive() {
+            if let Some(handshake) = packet.handshake {
+                self.handle_handshake(handshake);
                 continue;
-            } else {
-                self.last_message_sequence = message.sequence;
             }
 
-            // In this example entities represent the world state
-            if let Some(world_state) = message.state {
-                for state in world_state {
-                    if let Some(client_entity_id) = self.networked_entities.get(&state.entity_id) {
-                        // Found locally, update entity
-                        let entity = self.world.get_entity(*client_entity_id).unwrap();
-
-                        if self
-                            .controlled_entity
-                            .is_some_and(|id| id == *client_entity_id)
-                        {
-                            // Set authoriative position to whatever server says
-                            entity.position = state.position;
-
-                            if self.server_reconciliation_enabled {
-                                // Reconciliation
-                                // We re-apply all inputs that the server hasn't processed yet
-                                // This is based on the last processed input tick
-                                // We need to reapply up to the latest current tick
-                                let last_sync_tick = message.sequence + 1;
-
-                                // We only keep inputs that are newer than the last processed tick from server
-                                // So we're only removing stuff the server has already said it's processed
-                                self.input_history
-                                    .retain(|(input_tick, _)| *input_tick >= last_sync_tick);
-
-                                for (_input_tick, input) in &self.input_history {
-                                    //let entity = self.world.entities.get_mut(&state.entity_id).unwrap();
-                                    entity.integrate_input(&input);
+            let Some(connection) = self.connection.as_mut() else {
+                continue;
+            };
+
+            // Let the connection handle acks and throw away anything we've already had
+            for message in connection.receive(packet) {
+                // Events come reliably and in order on their own, so are never stale
+                for event in &message.events {
+                    self.handle_combat_event(event);
+                }
+                for entity_id in &message.despawns {
+                    self.despawn_entity(*entity_id);
+                }
+
+                // If message sequence is less than the last processed message
+                // we ignore it as it's out of sequence and therefore old
+                if message.sequence < self.last_message_sequence {
+                    continue;
+                } else {
+                    self.last_message_sequence = message.sequence;
+                }
+
+                // The sequence is the last input the server processed, so no need to resend it or earlier
+                self.unacked_inputs
+                    .retain(|(input_tick, _)| *input_tick > message.sequence);
+
+                if let Some(pong) = message.pong {
+                    self.clock_sync.pong(self.clock.now(), pong);
+                }
+
+                // Input sent before we synced was numbered with our own ticks
+                if let Some(lead) = message.input_lead.filter(|_| self.tick_synced) {
+                    self.record_input_lead(lead);
+                }
+
+                // Rebuild the full world state if we were only sent what changed
+                let world_state = match (message.state, message.delta) {
+                    (Some(world_state), _) => Some(
+                        world_state
+                            .into_iter()
+                            .map(|state| (state.entity_id, state))
+                            .collect::<HashMap<_, _>>(),
+                    ),
+                    (None, Some(delta)) => {
+                        let baseline = self
+                            .snapshot_baselines
+                            .iter()
+                            .find(|(baseline_tick, _)| *baseline_tick == delta.baseline_tick);
+
+                        // If we don't have the baseline any more the server has already moved
+                        // on to a newer one, so this message is old and can be dropped
+                        let world_state = baseline.map(|(_, baseline)| delta.apply(baseline));
+
+                        // The server will only delta against this baseline or newer from now on
+                        self.snapshot_baselines
+                            .retain(|(baseline_tick, _)| *baseline_tick >= delta.baseline_tick);
+
+                        world_state
+                    }
+                    (None, None) => None,
+                };
+
+                // Keep the full state as a baseline for later deltas and let the server know we have it
+                if let (Some(snapshot_tick), Some(world_state)) = (message.snapshot_tick, &world_state) {
+                    self.snapshot_baselines.push_back((snapshot_tick, world_state.clone()));
+                    if self.snapshot_baselines.len() > SNAPSHOT_BASELINE_LENGTH {
+                        self.snapshot_baselines.pop_front();
+                    }
+
+                    if self.latest_snapshot_tick.is_none_or(|latest| snapshot_tick > latest) {
+                        self.latest_snapshot_tick = Some(snapshot_tick);
+                    }
+
+                    self.prune_despawned_entities();
+                }
+
+                // In this example entities represent the world state
+                if let Some(world_state) = world_state {
+                    // New entities get our ids in the order they're made, so go by the
+                    // server's ids to make the same ones every run
+                    let mut world_state: Vec<State> = world_state.into_values().collect();
+                    world_state.sort_by_key(|state| state.entity_id);
+
+                    for state in world_state {
+                        // Sent before it was despawned and arrived after
+                        if self.despawned_entities.contains_key(&state.entity_id) {
+                            continue;
+                        }
+
+                        if let Some(client_entity_id) = self.networked_entities.get(&state.entity_id) {
+                            if let Some(snapshot_tick) = message.snapshot_tick {
+                                let last_seen = self.entity_last_seen.entry(*client_entity_id).or_insert(snapshot_tick);
+                                *last_seen = (*last_seen).max(snapshot_tick);
+                            }
+
+                            // Found locally, update entity
+                            let entity = self.world.get_entity(*client_entity_id).unwrap();
+
+                            if self
+                                .controlled_entity
+                                .is_some_and(|id| id == *client_entity_id)
+                            {
+                                // Set authoriative position to whatever server says
+                                entity.position = state.position;
+
+                                if self.server_reconciliation_enabled {
+                                    // Reconciliation
+                                    // We re-apply all inputs that the server hasn't processed yet
+                                    // This is based on the last processed input tick
+                                    // We need to reapply up to the latest current tick
+                                    let last_sync_tick = message.sequence + 1;
+
+                                    // We only keep inputs that are newer than the last processed tick from server
+                                    // So we're only removing stuff the server has already said it's processed
+                                    self.input_history
+                                        .retain(|(input_tick, _)| *input_tick >= last_sync_tick);
+
+                                    for (_input_tick, input) in &self.input_history {
+                                        //let entity = self.world.entities.get_mut(&state.entity_id).unwrap();
+                                        entity.integrate_input(input);
+                                    }
+                                } else {
+                                    // Disabled so drop all input history
+                                    self.input_history.clear();
                                 }
                             } else {
-                                // Disabled so drop all input history
-                                self.input_history.clear();
+                                if self.extrapolation_enabled {
+                                    // Store the state for use with extrapolation
+                                    // Anything older than what we have arrived out of order and is no use
+                                    let snapshots = self.state_snapshots.entry(*client_entity_id).or_default();
+                                    if let Some(snapshot_tick) = message.snapshot_tick {
+                                        if snapshots.back().is_none_or(|(newest, _)| snapshot_tick > *newest) {
+                                            snapshots.push_back((snapshot_tick, state));
+                                        }
+                                    }
+                                } else {
+                                    // Extrapolation disabled so just set the position
+                                    entity.position = state.position;
+                                }
                             }
                         } else {
-                            if self.extrapolation_enabled {
-                                // Store the state for use with extrapolation
-                                self.state_snapshots
-                                    .entry(*client_entity_id)
-                                    .or_insert_with(VecDeque::new)
-                                    .push_back((tick, state));
-                            } else {
-                                // Extrapolation disabled so just set the position
-                                entity.position = state.position;
+                            // Not found locally create entity
+                            let entity = Entity {
+                                position: state.position,
+                                speed: 5.0,
+                                colour: state.colour,
+                                ..Entity::new()
+                            };
+
+                            let client_entity_id = self.world.add_entity(entity);
+
+                            // Store the entity for later use
+                            self.networked_entities
+                                .insert(state.entity_id, client_entity_id);
+                            if let Some(snapshot_tick) = message.snapshot_tick {
+                                self.entity_last_seen.insert(client_entity_id, snapshot_tick);
                             }
                         }
-                    } else {
-                        // Not found locally create entity
-                        let entity = Entity {
-                            position: state.position,
-                            speed: 5.0,
-                            colour: state.colour,
-                        };
-
-                        let client_entity_id = self.world.add_entity(entity);
-
-                        // Store the entity for later use
-                        self.networked_entities
-                            .insert(state.entity_id, client_entity_id);
                     }
+
+                    self.expire_entities();
                 }
             }
         }
     }
 
-    fn interpolate_entities(&mut self, tick: i32) {
-        for (entity_id, entity) in &mut self.world.get_entities_mut().iter_mut() {
-            // Smoothing value
-            let smoothing_rate = 10;
+    // Remove our copy of an entity the server has removed
+    fn despawn_entity(&mut self, server_entity_id: i32) {
+        self.despawned_entities
+            .insert(server_entity_id, self.latest_snapshot_tick.unwrap_or_default());
+        self.forget_entity(server_entity_id);
+    }
+
+    // Stop remembering despawns once the server can't send them back to us.
+    // It only deltas against baselines we still have, so when all of those are
+    // newer than the despawn and none of them have the entity it's gone for good
+    fn prune_despawned_entities(&mut self) {
+        let Some((oldest_baseline_tick, _)) = self.snapshot_baselines.front() else {
+            return;
+        };
+        let baselines = &self.snapshot_baselines;
+        self.despawned_entities.retain(|server_entity_id, despawned_at| {
+            *despawned_at >= *oldest_baseline_tick
+                || baselines
+                    .iter()
+                    .any(|(_, baseline)| baseline.contains_key(server_entity_id))
+        });
+    }
+
+    // Remove our copy of a server entity. It'll be made again if it turns up in a snapshot
+    fn forget_entity(&mut self, server_entity_id: i32) {
+        let Some(client_entity_id) = self.networked_entities.remove(&server_entity_id) else {
+            return;
+        };
+        self.world.remove_entity(client_entity_id);
+        self.state_snapshots.remove(&client_entity_id);
+        self.entity_last_seen.remove(&client_entity_id);
 
-            // This tick should probably match the server?
-            let render_tick = tick - smoothing_rate;
+        if self.controlled_entity == Some(client_entity_id) {
+            self.controlled_entity = None;
+            self.input_history.clear();
+        }
+    }
 
+    // Remove other entities that haven't been in a snapshot for a while
+    fn expire_entities(&mut self) {
+        let Some(latest_snapshot_tick) = self.latest_snapshot_tick else {
+            return;
+        };
+
+        let expired: Vec<i32> = self
+            .networked_entities
+            .iter()
+            .filter(|(_, client_entity_id)| self.controlled_entity != Some(**client_entity_id))
+            .filter(|(_, client_entity_id)| {
+                self.entity_last_seen
+                    .get(client_entity_id)
+                    .is_none_or(|last_seen| latest_snapshot_tick - last_seen > self.entity_timeout_ticks)
+            })
+            .map(|(server_entity_id, _)| *server_entity_id)
+            .collect();
+
+        for server_entity_id in expired {
+            self.forget_entity(server_entity_id);
+        }
+    }
+
+    // Our entity for one of the server's
+    fn local_entity(&self, server_entity_id: i32) -> Option<i32> {
+        self.networked_entities.get(&server_entity_id).copied()
+    }
+
+    fn handle_combat_event(&mut self, event: &CombatEvent) {
+        match *event {
+            CombatEvent::Shot { from, to, .. } => {
+                self.tracers.push(Tracer {
+                    from,
+                    to,
+                    ticks_left: combat::TRACER_TICKS,
+                });
+            }
+            CombatEvent::ProjectileFired {
+                projectile_id,
+                mut projectile,
+            } => {
+                // We move it along ourselves from here until it hits or runs out
+                projectile.owner = self.local_entity(projectile.owner).unwrap_or(-1);
+                self.world.insert_projectile(projectile_id, projectile);
+            }
+            CombatEvent::Hit {
+                target,
+                projectile,
+                health,
+                ..
+            } => {
+                if let Some(projectile_id) = projectile {
+                    self.world.remove_projectile(projectile_id);
+                }
+                if let Some(entity) = self.local_entity(target).and_then(|id| self.world.get_entity(id)) {
+                    entity.health = health;
+                }
+            }
+            CombatEvent::Kill { shooter, target } => {
+                let target = self.local_entity(target);
+                if let Some(entity) = target.and_then(|id| self.world.get_entity(id)) {
+                    entity.health = MAX_HEALTH;
+                }
+
+                if let (Some(shooter), Some(target)) = (self.local_entity(shooter), target) {
+                    self.kill_feed.push_back((shooter, target));
+                    if self.kill_feed.len() > KILL_FEED_LENGTH {
+                        self.kill_feed.pop_front();
+                    }
+                }
+            }
+        }
+    }
+
+    // Move projectiles along and fade out tracers
+    fn update_combat(&mut self) {
+        self.world.update_projectiles();
+
+        self.tracers.retain_mut(|tracer| {
+            tracer.ticks_left -= 1;
+            tracer.ticks_left > 0
+        });
+    }
+
+    // The server tick other entities are drawn at, far enough behind the newest
+    // snapshot that there's usually one either side of it to blend between
+    fn render_tick(&self) -> Option<f32> {
+        let now = self.clock.now();
+        match (self.clock_sync.server_tick(now), self.clock_sync.tick_interval()) {
+            (Some(server_tick), Some(tick_interval)) => {
+                // Snapshots take half the round trip to get here
+                let one_way_ticks = (self.clock_sync.rtt / 2).as_secs_f64() / tick_interval.as_secs_f64();
+                Some((server_tick - one_way_ticks) as f32 - self.interpolation_delay_ticks)
+            }
+            // Not synced yet so just trail the newest snapshot
+            _ => self
+                .latest_snapshot_tick
+                .map(|tick| tick as f32 - self.interpolation_delay_ticks),
+        }
+    }
+
+    fn interpolate_entities(&mut self) {
+        let Some(render_tick) = self.render_tick() else {
+            return;
+        };
+        self.view_tick = Some(render_tick);
+
+        for (entity_id, entity) in &mut self.world.get_entities_mut().iter_mut() {
             // Ignore the controlled entity
             if self.controlled_entity.is_some_and(|id| id == *entity_id) {
-                //println!("Ignoring controlled entity {}", entity_id);
                 continue;
             }
 
-            // If we have more than 2 snapshots keep the latest 2
+            // Drop snapshots once the one after them is behind the render tick,
+            // we only need the two either side of it
             self.state_snapshots
                 .entry(*entity_id)
                 .and_modify(|snapshots| {
-                    if snapshots.len() > 2 && snapshots[1].0 <= render_tick {
+                    while snapshots.len() > 2 && snapshots[1].0 as f32 <= render_tick {
                         snapshots.pop_front();
                     }
                 });
 
             // If we have a snapshot for this entity
             if let Some(snapshots) = self.state_snapshots.get(entity_id) {
-                 // Interpolate between the two latest snapshots
-                 if snapshots.len() >= 2 {
-                    // Drop the older snapshots
-                    // while let Some((snapshot_tick, _)) = snapshots.get(1) {
-                    //     if snapshots.len() >= 2 && *snapshot_tick <= render_tick {
-                    //         snapshots.pop_front();
-                    //     } else {
-                    //         break;
-                    //     }
-                    // }
-
-                    if let Some((snapshot1_tick, snapshot1_state)) = snapshots.get(0) {
-                        if let Some((snapshot2_tick, snapshot2_state)) = snapshots.get(1) {
-                            if snapshot1_tick <= &render_tick && snapshot2_tick >= &render_tick {
-                                let x0 = snapshot1_state.position.0;
-                                let x1 = snapshot2_state.position.0;
-                                let y0 = snapshot1_state.position.1;
-                                let y1 = snapshot2_state.position.1;
-
-                                let t0 = snapshot1_tick;
-                                let t1 = snapshot2_tick;
-
-                                // Difference between the two snapshots
-                                let delta = t1 - t0;
-                                let time_since_snapshot = render_tick - t0;
-                                let lerp_fac = time_since_snapshot as f32 / delta as f32;
-
-                                let position = (x0 + (x1 - x0) * lerp_fac, y0 + (y1 - y0) * lerp_fac);
-
-                                entity.position = position;
-                            }
-                        }
+                // Interpolate between the two snapshots either side of the render tick
+                if let (Some((snapshot1_tick, snapshot1_state)), Some((snapshot2_tick, snapshot2_state))) =
+                    (snapshots.front(), snapshots.get(1))
+                {
+                    let t0 = *snapshot1_tick as f32;
+                    let t1 = *snapshot2_tick as f32;
+
+                    if t0 <= render_tick && render_tick <= t1 {
+                        let x0 = snapshot1_state.position.0;
+                        let x1 = snapshot2_state.position.0;
+                        let y0 = snapshot1_state.position.1;
+                        let y1 = snapshot2_state.position.1;
+
+                        // Difference between the two snapshots
+                        let delta = t1 - t0;
+                        let time_since_snapshot = render_tick - t0;
+                        let lerp_fac = time_since_snapshot / delta;
+
+                        let position = (x0 + (x1 - x0) * lerp_fac, y0 + (y1 - y0) * lerp_fac);
+
+                        entity.position = position;
                     }
                 }
             }
         }
     }
 
+    // Input to send on the next tick, for driving the client without a keyboard
+    pub fn set_input(&mut self, input: Input) {
+        self.input_state = Some(input);
+    }
+
     /// Gets the current input state
     fn get_input(&mut self) {
         let left: bool;
         let right: bool;
         let up: bool;
         let down: bool;
+        let fire: bool;
 
         if !self.use_alternate_input {
             left = is_key_down(KeyCode::A);
             right = is_key_down(KeyCode::D);
             up = is_key_down(KeyCode::W);
             down = is_key_down(KeyCode::S);
+            fire = is_key_down(KeyCode::Space);
         } else {
             left = is_key_down(KeyCode::Left);
             right = is_key_down(KeyCode::Right);
             up = is_key_down(KeyCode::Up);
             down = is_key_down(KeyCode::Down);
+            fire = is_key_down(KeyCode::Enter);
         }
 
-        if left || right || up || down {
+        if left || right || up || down || fire {
             self.input_state = Some(Input {
                 left,
                 right,
                 up,
                 down,
+                fire,
             });
         }
     }
 
-    fn process_input(&mut self) {
-        if let Some(server_network) = &self.server_network {
-            let mut server_network = server_network.borrow_mut();
+    // Which tick the server is on right now, once we've heard back from it
+    pub fn estimated_server_tick(&self) -> Option<f64> {
+        self.clock_sync.server_tick(self.clock.now())
+    }
+
+    // Keep our tick ahead of the server's by enough that input arrives just
+    // before the server simulates the tick it was made for
+    fn sync_tick(&mut self) {
+        let Some(target_tick) = self.clock_sync.target_tick(self.clock.now()) else {
+            return;
+        };
+
+        if !self.tick_synced {
+            // Ticks only line up if we're running at the same rate as the server
+            if let Some(tick_interval) = self.clock_sync.tick_interval() {
+                self.tick_timer.tick_interval = tick_interval;
+                self.tick_rate_ms = tick_interval.as_millis() as u64;
+            }
+
+            // Anything we predicted so far was numbered with our old ticks
+            self.tick_timer.current_tick = target_tick;
+            self.input_history.clear();
+            self.unacked_inputs.clear();
+            self.tick_synced = true;
+            return;
+        }
 
-            if let Some(input_state) = self.input_state.take() {
+        // Falling behind means input arriving after the server needed it, so catch
+        // straight up. Jumping backwards would reuse input numbers the server has
+        // already seen, so being too far ahead is left alone
+        if target_tick - self.tick_timer.current_tick > MAX_TICK_DRIFT {
+            self.tick_timer.current_tick = target_tick;
+        }
+    }
+
+    // Run our ticks slightly faster or slower until input is reaching the
+    // server the target number of ticks early, aiming further ahead the more
+    // the lead varies
+    fn record_input_lead(&mut self, lead: i32) {
+        let lead = lead as f32;
+        let smoothed = match self.input_lead {
+            Some(input_lead) => {
+                self.input_lead_jitter = self.input_lead_jitter * 0.9 + (lead - input_lead).abs() * 0.1;
+                input_lead * 0.9 + lead * 0.1
+            }
+            None => lead,
+        };
+        self.input_lead = Some(smoothed);
+        self.target_input_lead = self.min_input_lead + self.input_lead_jitter * INPUT_LEAD_JITTER_MARGIN;
+
+        // Too early and we stretch the interval to let the server catch up,
+        // too late and we squeeze it to get further ahead
+        let error = (smoothed - self.target_input_lead) as f64;
+        self.tick_timer.dilation =
+            1.0 + (error * TICK_DILATION_PER_TICK).clamp(-MAX_TICK_DILATION, MAX_TICK_DILATION);
+    }
+
+    fn process_input(&mut self, tick: i32) {
+        if let Some(connection) = &mut self.connection {
+            let ping = self.clock_sync.ping(self.clock.now());
+
+            let input_state = self
+                .input_state
+                .take()
+                .or_else(|| self.send_idle_input.then(Input::default));
+
+            // Other entities are where the newest snapshot put them unless we're blending
+            let view_tick = if self.extrapolation_enabled {
+                self.view_tick
+            } else {
+                self.latest_snapshot_tick.map(|tick| tick as f32)
+            };
+
+            if let Some(input_state) = input_state {
                 // Send an update to server with the latest input
                 // We also send the local tick this can then
                 // be sent back and later used for reconciliation the
                 // differences between client and server.
-                server_network.send(
-                    self.id,
-                    Message {
-                        state: None,
-                        // We can use the current tick as the input sequence number
-                        sequence: self.tick_timer.current_tick,
-                        input: Some((
-                            input_state.left,
-                            input_state.right,
-                            input_state.up,
-                            input_state.down,
-                        )),
-                    },
-                );
+                // Along with the last few the server hasn't confirmed, in case they were lost
+                let previous_inputs = self
+                    .unacked_inputs
+                    .iter()
+                    .rev()
+                    .take(self.redundant_inputs)
+                    .copied()
+                    .collect();
+
+                connection.send(Message {
+                    state: None,
+                    // We can use the tick as the input sequence number
+                    sequence: tick,
+                    input: Some(input_state),
+                    snapshot_ack: self.latest_snapshot_tick,
+                    ping,
+                    previous_inputs,
+                    view_tick,
+                    ..Default::default()
+                });
+
+                self.unacked_inputs.push_back((tick, input_state));
+                // Only ever resend the newest few
+                while self.unacked_inputs.len() > self.redundant_inputs {
+                    self.unacked_inputs.pop_front();
+                }
+                self.acked_snapshot_tick = self.latest_snapshot_tick;
 
                 // Client side prediction
                 // We let the client carry out it's local simulation changes
                 if self.client_prediction_enabled {
-                    let controlled_client_entity_id = self.controlled_entity.unwrap();
                     if let Some(entity) = self
-                        .world
-                        .get_entity(controlled_client_entity_id)
+                        .controlled_entity
+                        .and_then(|entity_id| self.world.get_entity(entity_id))
                     {
                         entity.integrate_input(&input_state);
                     }
                 }
 
                 // Store the input for reconciliation
-                self.input_history
-                    .push_back((self.tick_timer.current_tick, input_state));
+                self.input_history.push_back((tick, input_state));
+            } else if self.latest_snapshot_tick != self.acked_snapshot_tick || ping.is_some() {
+                // No input to piggyback on, but the server still needs to know
+                // which snapshot we have so it can delta against it
+                connection.send(Message {
+                    sequence: tick,
+                    snapshot_ack: self.latest_snapshot_tick,
+                    ping,
+                    ..Default::default()
+                });
+                self.acked_snapshot_tick = self.latest_snapshot_tick;
             }
+
+            connection.update();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::ManualClock,
+        net::{seeded_network, LinkConditions},
+        server::Server,
+    };
+
+    fn client() -> Client {
+        let clock: SharedClock = Rc::new(ManualClock::new());
+        Client::with_rng_and_clock(1, 16, seeded_network(1, &clock), SimRng::new(1), clock)
+    }
+
+    #[test]
+    fn steady_input_lead_keeps_the_minimum_target() {
+        let mut client = client();
+        for _ in 0..50 {
+            client.record_input_lead(1);
+        }
+
+        assert_eq!(client.input_lead, Some(1.0));
+        assert_eq!(client.input_lead_jitter, 0.0);
+        assert_eq!(client.target_input_lead, client.min_input_lead);
+        assert_eq!(client.tick_timer.dilation, 1.0);
+    }
+
+    #[test]
+    fn jittery_input_lead_raises_the_target() {
+        let mut client = client();
+        for lead in [0, 4].iter().cycle().take(100) {
+            client.record_input_lead(*lead);
+        }
+
+        assert!(client.input_lead_jitter > 1.0);
+        assert!(client.target_input_lead > client.min_input_lead + 2.0);
+
+        // Settling down brings it back
+        for _ in 0..200 {
+            client.record_input_lead(3);
+        }
+        assert!(client.target_input_lead < client.min_input_lead + 0.1);
+    }
+
+    #[test]
+    fn input_lead_dilates_ticks_towards_the_target() {
+        // Too early, so we slow down and let the server catch up
+        let mut early = client();
+        early.record_input_lead(2);
+        assert!(early.tick_timer.dilation > 1.0);
+
+        // Too late, so we speed up
+        let mut late = client();
+        late.record_input_lead(0);
+        assert!(late.tick_timer.dilation < 1.0);
+
+        // Never by more than the limit however far off it is
+        let mut far_early = client();
+        far_early.record_input_lead(100);
+        assert_eq!(far_early.tick_timer.dilation, 1.0 + MAX_TICK_DILATION);
+        let mut far_late = client();
+        far_late.record_input_lead(-100);
+        assert_eq!(far_late.tick_timer.dilation, 1.0 - MAX_TICK_DILATION);
+    }
+
+    #[test]
+    fn despawns_are_forgotten_once_no_baseline_can_bring_them_back() {
+        let clock = ManualClock::new();
+        let shared_clock: SharedClock = Rc::new(clock.clone());
+        let mut server =
+            Server::with_rng_and_clock(16, seeded_network(1, &shared_clock), SimRng::new(1), Rc::clone(&shared_clock));
+        let mut client =
+            Client::with_rng_and_clock(1, 16, seeded_network(2, &shared_clock), SimRng::new(2), Rc::clone(&shared_clock));
+        client.keyboard_input_enabled = false;
+        let conditions = LinkConditions {
+            min_latency_ms: 20,
+            max_latency_ms: 20,
+            ..Default::default()
+        };
+        server.create_npc_entities();
+        client.connect(&mut server, conditions, conditions);
+
+        let run_for = |client: &mut Client, server: &mut Server, millis: u64| {
+            for _ in 0..millis / 5 {
+                client.update();
+                server.update();
+                clock.advance(std::time::Duration::from_millis(5));
+            }
+        };
+        run_for(&mut client, &mut server, 500);
+        assert_eq!(client.world.get_entities().len(), 2);
+
+        server.remove_npc_entity();
+        run_for(&mut client, &mut server, 100);
+        assert_eq!(client.world.get_entities().len(), 1);
+        assert_eq!(client.despawned_entities.len(), 1);
+
+        // Every baseline from before the despawn gets replaced in time
+        run_for(&mut client, &mut server, 500);
+        assert!(client.despawned_entities.is_empty());
+        assert_eq!(client.world.get_entities().len(), 1);
+    }
+}
@@ -0,0 +1,126 @@
+use std::{collections::VecDeque, time::Duration};
+
+use crate::net::Pong;
+
+// How many recent round trips we pick the best estimate from
+const SAMPLE_COUNT: usize = 8;
+
+// Anything slower than this is a stale or mangled pong
+const MAX_RTT: Duration = Duration::from_secs(10);
+
+struct Sample {
+    rtt: Duration,
+    // Server tick at client time zero, in fractions of a tick
+    offset_ticks: f64,
+}
+
+/// Client side estimate of the server's tick, from pings the server echoes back.
+///
+/// Each pong says which tick the server was on when it replied. Assuming the
+/// reply took half the round trip to get here gives the server tick at the time
+/// it arrived. Queueing only ever makes a round trip longer, so the estimate from
+/// the quickest recent round trip is the one trusted.
+pub struct ClockSync {
+    // How often to ping the server
+    pub ping_interval: Duration,
+    // Extra lead on top of half the round trip, to soak up jitter
+    pub safety_margin: Duration,
+    // Smoothed round trip time from pongs
+    pub rtt: Duration,
+
+    // The server's tick interval, from the last pong
+    tick_interval: Option<Duration>,
+    samples: VecDeque<Sample>,
+    last_ping: Option<Duration>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        ClockSync {
+            ping_interval: Duration::from_millis(500),
+            safety_margin: Duration::from_millis(20),
+            rtt: Duration::ZERO,
+            tick_interval: None,
+            samples: VecDeque::new(),
+            last_ping: None,
+        }
+    }
+
+    // Forget everything, e.g. when connecting to a different server
+    pub fn reset(&mut self) {
+        self.rtt = Duration::ZERO;
+        self.tick_interval = None;
+        self.samples.clear();
+        self.last_ping = None;
+    }
+
+    // The time to put in a ping if one is due, the server echoes it back in a pong
+    pub fn ping(&mut self, now: Duration) -> Option<u32> {
+        if self
+            .last_ping
+            .is_some_and(|last_ping| now.saturating_sub(last_ping) < self.ping_interval)
+        {
+            return None;
+        }
+
+        self.last_ping = Some(now);
+        Some(now.as_millis() as u32)
+    }
+
+    pub fn pong(&mut self, now: Duration, pong: Pong) {
+        // Ping times wrap, so take the difference the same way
+        let rtt = Duration::from_millis((now.as_millis() as u32).wrapping_sub(pong.ping_time) as u64);
+        if rtt > MAX_RTT {
+            return;
+        }
+
+        let tick_interval = Duration::from_millis(pong.tick_interval_ms.max(1) as u64);
+        self.tick_interval = Some(tick_interval);
+
+        // Server tick when the pong arrived, minus however far our clock had got by then
+        let one_way_ticks = (rtt / 2).as_secs_f64() / tick_interval.as_secs_f64();
+        let offset_ticks = pong.server_tick as f64 + one_way_ticks - now.as_secs_f64() / tick_interval.as_secs_f64();
+
+        self.samples.push_back(Sample { rtt, offset_ticks });
+        if self.samples.len() > SAMPLE_COUNT {
+            self.samples.pop_front();
+        }
+
+        self.rtt = if self.rtt.is_zero() {
+            rtt
+        } else {
+            self.rtt.mul_f32(0.9) + rtt.mul_f32(0.1)
+        };
+    }
+
+    pub fn is_synced(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    pub fn tick_interval(&self) -> Option<Duration> {
+        self.tick_interval
+    }
+
+    // Which tick the server is on right now, including how far it is into it
+    pub fn server_tick(&self, now: Duration) -> Option<f64> {
+        let tick_interval = self.tick_interval?;
+        let best = self.samples.iter().min_by_key(|sample| sample.rtt)?;
+
+        Some(best.offset_ticks + now.as_secs_f64() / tick_interval.as_secs_f64())
+    }
+
+    // The tick the client should be on so that input sent now reaches the server
+    // just before it simulates that tick
+    pub fn target_tick(&self, now: Duration) -> Option<i32> {
+        let tick_interval = self.tick_interval?;
+        let lead = (self.rtt / 2 + self.safety_margin).as_secs_f64() / tick_interval.as_secs_f64();
+
+        Some((self.server_tick(now)? + lead).ceil() as i32)
+    }
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
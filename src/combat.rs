@@ -0,0 +1,108 @@
+use crate::{
+    lagcompensation::Positions,
+    sim::{Projectile, ENTITY_SIZE},
+};
+
+pub const HITSCAN_RANGE: f32 = 600.0;
+pub const HITSCAN_DAMAGE: i32 = 25;
+
+// Distance a projectile moves each tick and how long it lasts
+pub const PROJECTILE_SPEED: f32 = 15.0;
+pub const PROJECTILE_LIFETIME_TICKS: i32 = 40;
+pub const PROJECTILE_DAMAGE: i32 = 50;
+
+// Ticks between shots while fire is held down
+pub const FIRE_COOLDOWN_TICKS: i32 = 5;
+
+// How long a hitscan shot stays on screen
+pub const TRACER_TICKS: i32 = 6;
+
+/// What players shoot with
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weapon {
+    /// Hits instantly along a line, checked against where the shooter saw everyone
+    #[default]
+    Hitscan,
+    /// Fires something that travels, checked every tick it's in flight
+    Projectile,
+}
+
+/// Something that happened in a fight, sent reliably to every client.
+/// Entity ids are the server's
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CombatEvent {
+    /// A hitscan shot and where it stopped, for drawing a tracer
+    Shot {
+        shooter: i32,
+        from: (f32, f32),
+        to: (f32, f32),
+    },
+    /// A projectile was fired, clients move it along themselves from here
+    ProjectileFired {
+        projectile_id: i32,
+        projectile: Projectile,
+    },
+    /// The target took damage, from a projectile if one is given
+    Hit {
+        shooter: i32,
+        target: i32,
+        projectile: Option<i32>,
+        health: i32,
+    },
+    /// The target ran out of health and was respawned
+    Kill { shooter: i32, target: i32 },
+}
+
+/// A hitscan shot being drawn, fading out over a few ticks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tracer {
+    pub from: (f32, f32),
+    pub to: (f32, f32),
+    pub ticks_left: i32,
+}
+
+// How far along a ray it enters the square of an entity at position, if it does
+pub fn ray_hits_square(from: (f32, f32), direction: (f32, f32), range: f32, position: (f32, f32)) -> Option<f32> {
+    let mut near: f32 = 0.0;
+    let mut far = range;
+
+    // Clip the ray against each pair of sides in turn
+    for (origin, direction, min) in [(from.0, direction.0, position.0), (from.1, direction.1, position.1)] {
+        let max = min + ENTITY_SIZE;
+        if direction == 0.0 {
+            // Parallel to these sides so it has to start between them
+            if origin < min || origin > max {
+                return None;
+            }
+            continue;
+        }
+
+        let t0 = (min - origin) / direction;
+        let t1 = (max - origin) / direction;
+        near = near.max(t0.min(t1));
+        far = far.min(t0.max(t1));
+        if near > far {
+            return None;
+        }
+    }
+
+    Some(near)
+}
+
+// The nearest entity a ray hits and how far along it is, ignoring the shooter.
+// Ties go to the lowest id so the result doesn't depend on the map's order
+pub fn raycast(
+    from: (f32, f32),
+    direction: (f32, f32),
+    range: f32,
+    positions: &Positions,
+    ignore: i32,
+) -> Option<(i32, f32)> {
+    positions
+        .iter()
+        .filter(|(entity_id, _)| **entity_id != ignore)
+        .filter_map(|(entity_id, position)| {
+            ray_hits_square(from, direction, range, *position).map(|distance| (*entity_id, distance))
+        })
+        .min_by(|(a_id, a), (b_id, b)| a.total_cmp(b).then(a_id.cmp(b_id)))
+}
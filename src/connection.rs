@@ -0,0 +1,497 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    rc::Rc,
+    time::Duration,
+};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    net::{Channel, Message, PacketHeader, SharedTransport},
+    wire,
+};
+
+// How many sequences per channel we remember for throwing away duplicates
+const RECEIVED_RELIABLE_HISTORY: usize = 1024;
+
+// Sent packets older than this many sequences can never be acked
+const SENT_PACKET_HISTORY: usize = 1024;
+
+// Is sequence a newer than b, allowing for wrap around
+pub fn sequence_greater_than(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < u16::MAX / 2
+}
+
+struct SentPacket {
+    sent_at: Duration,
+    // The reliable message this packet carried, if any
+    reliable: Option<(Channel, u16)>,
+}
+
+struct PendingReliable {
+    message: Message,
+    last_sent: Duration,
+}
+
+// Sequencing for one reliable channel, each direction counts separately
+#[derive(Default)]
+struct ReliableChannel {
+    // Sequence for the next message we send
+    next_sequence: u16,
+    // Messages waiting to be acked, by sequence
+    pending: BTreeMap<u16, PendingReliable>,
+
+    // Unordered: sequences we've already let through, oldest first
+    received: VecDeque<u16>,
+    received_set: HashSet<u16>,
+
+    // Ordered: the sequence we're waiting on and anything that arrived ahead of it
+    next_expected: u16,
+    buffered: BTreeMap<u16, Message>,
+}
+
+/// One end of a connection to a remote endpoint, layered over its transport.
+///
+/// Every packet sent carries a sequence number along with the latest sequence we
+/// received from the other end and a bitfield of the 32 before it. When a packet
+/// is acked anything reliable it carried is delivered, anything reliable that
+/// isn't acked in time is sent again in a new packet.
+///
+/// Messages are sent on a channel. Unreliable messages go out once, the reliable
+/// channels each have their own sequence so an ordered message waiting on a resend
+/// doesn't hold up unordered ones.
+///
+/// Acks ride along on whatever else is sent. When there's been nothing to send for
+/// a while an empty keepalive packet goes out instead, so the other end can tell
+/// we're still here and its acks still get through.
+pub struct Connection {
+    local_id: i32,
+    // The remote endpoints inbox
+    outgoing: SharedTransport,
+    clock: SharedClock,
+
+    // Sequence for the next packet we send
+    local_sequence: u16,
+    // Newest sequence received from the remote and which of the 32 before it arrived
+    remote_sequence: Option<u16>,
+    received_bits: u32,
+
+    sent_packets: HashMap<u16, SentPacket>,
+
+    reliable_unordered: ReliableChannel,
+    reliable_ordered: ReliableChannel,
+
+    // Smoothed round trip time from acks
+    pub rtt: Duration,
+    // How long past the round trip time we wait before resending
+    pub resend_after: Duration,
+    // Longest we go without sending anything before sending a keepalive
+    pub keepalive_interval: Duration,
+
+    // When we last sent and received any packet, received includes duplicates
+    last_packet_sent: Duration,
+    last_packet_received: Duration,
+    // Encoded size of the last packet sent, header and all
+    last_packet_bytes: usize,
+
+    on_delivered: Option<Box<dyn FnMut(Channel, u16)>>,
+}
+
+impl Connection {
+    pub fn new(local_id: i32, outgoing: SharedTransport) -> Self {
+        Self::with_clock(local_id, outgoing, Rc::new(SystemClock::new()))
+    }
+
+    // Resends and round trip times are timed with the given clock
+    pub fn with_clock(local_id: i32, outgoing: SharedTransport, clock: SharedClock) -> Self {
+        let now = clock.now();
+        Connection {
+            local_id,
+            outgoing,
+            clock,
+            local_sequence: 0,
+            remote_sequence: None,
+            received_bits: 0,
+            sent_packets: HashMap::new(),
+            reliable_unordered: ReliableChannel::default(),
+            reliable_ordered: ReliableChannel::default(),
+            rtt: Duration::ZERO,
+            resend_after: Duration::from_millis(100),
+            keepalive_interval: Duration::from_millis(250),
+            last_packet_sent: now,
+            last_packet_received: now,
+            last_packet_bytes: 0,
+            on_delivered: None,
+        }
+    }
+
+    pub fn get_network(&self) -> SharedTransport {
+        self.outgoing.clone()
+    }
+
+    // Called with the channel and sequence returned by send_on once a reliable
+    // message has been acked
+    pub fn on_delivered(&mut self, callback: impl FnMut(Channel, u16) + 'static) {
+        self.on_delivered = Some(Box::new(callback));
+    }
+
+    // Reliable messages sent but not acked yet
+    pub fn pending_reliable(&self) -> usize {
+        self.reliable_unordered.pending.len() + self.reliable_ordered.pending.len()
+    }
+
+    // How long since anything arrived from the other end, or since the
+    // connection was made if nothing has
+    pub fn time_since_received(&self) -> Duration {
+        self.clock.now().saturating_sub(self.last_packet_received)
+    }
+
+    pub fn last_packet_bytes(&self) -> usize {
+        self.last_packet_bytes
+    }
+
+    // Send a message that may never arrive
+    pub fn send(&mut self, message: Message) {
+        self.send_on(Channel::Unreliable, message);
+    }
+
+    // Send a message on the given channel.
+    // Returns its sequence on that channel, unreliable messages don't have one so get 0
+    pub fn send_on(&mut self, channel: Channel, mut message: Message) -> u16 {
+        message.channel = channel;
+        let now = self.clock.now();
+
+        let Some(reliable) = self.reliable_channel(channel) else {
+            message.channel_sequence = None;
+            self.send_packet(message, None);
+            return 0;
+        };
+
+        let sequence = reliable.next_sequence;
+        reliable.next_sequence = reliable.next_sequence.wrapping_add(1);
+
+        message.channel_sequence = Some(sequence);
+        reliable.pending.insert(
+            sequence,
+            PendingReliable {
+                message: message.clone(),
+                last_sent: now,
+            },
+        );
+        self.send_packet(message, Some((channel, sequence)));
+
+        sequence
+    }
+
+    // Handle a message that arrived from the remote end.
+    // Returns the messages that are ready to be processed, which can be none for
+    // duplicates or several when an ordered message fills a gap
+    pub fn receive(&mut self, message: Message) -> Vec<Message> {
+        // Messages from something that isn't a connection pass straight through
+        let Some(packet) = message.packet else {
+            return vec![message];
+        };
+
+        // Even a duplicate means they're still there
+        self.last_packet_received = self.clock.now();
+
+        if !self.record_received(packet.sequence) {
+            return Vec::new();
+        }
+
+        if let Some(ack) = packet.ack {
+            self.process_acks(ack, packet.ack_bits);
+        }
+
+        let channel = message.channel;
+        let Some(sequence) = message.channel_sequence else {
+            return vec![message];
+        };
+        let Some(reliable) = self.reliable_channel(channel) else {
+            return vec![message];
+        };
+
+        match channel {
+            Channel::ReliableOrdered => {
+                // Already had it
+                if sequence != reliable.next_expected
+                    && !sequence_greater_than(sequence, reliable.next_expected)
+                {
+                    return Vec::new();
+                }
+
+                // Hold on to it until everything before it has arrived
+                reliable.buffered.insert(sequence, message);
+
+                let mut ready = Vec::new();
+                while let Some(next) = reliable.buffered.remove(&reliable.next_expected) {
+                    ready.push(next);
+                    reliable.next_expected = reliable.next_expected.wrapping_add(1);
+                }
+                ready
+            }
+            _ => {
+                // Resends can arrive more than once in different packets
+                if !reliable.received_set.insert(sequence) {
+                    return Vec::new();
+                }
+                reliable.received.push_back(sequence);
+                if reliable.received.len() > RECEIVED_RELIABLE_HISTORY {
+                    if let Some(oldest) = reliable.received.pop_front() {
+                        reliable.received_set.remove(&oldest);
+                    }
+                }
+
+                vec![message]
+            }
+        }
+    }
+
+    // Resend reliable messages that haven't been acked in time.
+    // Should be called once per tick after sending
+    pub fn update(&mut self) {
+        let now = self.clock.now();
+        let resend_after = self.rtt + self.resend_after;
+
+        for channel in [Channel::ReliableUnordered, Channel::ReliableOrdered] {
+            let reliable = self.reliable_channel(channel).unwrap();
+
+            let resends: Vec<(u16, Message)> = reliable
+                .pending
+                .iter_mut()
+                .filter(|(_, pending)| now.saturating_sub(pending.last_sent) >= resend_after)
+                .map(|(sequence, pending)| {
+                    pending.last_sent = now;
+                    (*sequence, pending.message.clone())
+                })
+                .collect();
+
+            for (sequence, message) in resends {
+                self.send_packet(message, Some((channel, sequence)));
+            }
+        }
+
+        if now.saturating_sub(self.last_packet_sent) >= self.keepalive_interval {
+            self.send_packet(Message::default(), None);
+        }
+    }
+
+    fn reliable_channel(&mut self, channel: Channel) -> Option<&mut ReliableChannel> {
+        match channel {
+            Channel::Unreliable => None,
+            Channel::ReliableUnordered => Some(&mut self.reliable_unordered),
+            Channel::ReliableOrdered => Some(&mut self.reliable_ordered),
+        }
+    }
+
+    fn send_packet(&mut self, mut message: Message, reliable: Option<(Channel, u16)>) {
+        let sequence = self.local_sequence;
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+
+        message.packet = Some(PacketHeader {
+            sequence,
+            ack: self.remote_sequence,
+            ack_bits: self.received_bits,
+        });
+
+        let now = self.clock.now();
+        self.last_packet_sent = now;
+        self.last_packet_bytes = wire::encode_message(&message).len();
+        self.sent_packets.insert(
+            sequence,
+            SentPacket {
+                sent_at: now,
+                reliable,
+            },
+        );
+        // Forget about packets too old to ever be acked
+        self.sent_packets
+            .remove(&sequence.wrapping_sub(SENT_PACKET_HISTORY as u16));
+
+        self.outgoing.borrow_mut().send(self.local_id, message);
+    }
+
+    // Track the sequence for acking, returns false if we've already had it
+    fn record_received(&mut self, sequence: u16) -> bool {
+        let Some(remote_sequence) = self.remote_sequence else {
+            self.remote_sequence = Some(sequence);
+            return true;
+        };
+
+        if sequence_greater_than(sequence, remote_sequence) {
+            // Shift the window along, the previous newest is now one of the bits
+            let shift = sequence.wrapping_sub(remote_sequence) as u32;
+            self.received_bits = if shift > 32 {
+                0
+            } else {
+                ((self.received_bits as u64) << shift) as u32 | 1 << (shift - 1)
+            };
+            self.remote_sequence = Some(sequence);
+            true
+        } else {
+            let age = remote_sequence.wrapping_sub(sequence) as u32;
+            if age == 0 {
+                return false;
+            }
+            // Too old to ack, treat it like it was lost
+            if age > 32 {
+                return false;
+            }
+
+            let bit = 1 << (age - 1);
+            if self.received_bits & bit != 0 {
+                return false;
+            }
+            self.received_bits |= bit;
+            true
+        }
+    }
+
+    fn process_acks(&mut self, ack: u16, ack_bits: u32) {
+        self.process_ack(ack);
+        for bit in 0..32 {
+            if ack_bits & (1 << bit) != 0 {
+                self.process_ack(ack.wrapping_sub(bit + 1));
+            }
+        }
+    }
+
+    fn process_ack(&mut self, sequence: u16) {
+        let Some(sent) = self.sent_packets.remove(&sequence) else {
+            return;
+        };
+
+        // Smooth the round trip time so one slow packet doesn't throw it
+        let rtt = self.clock.now().saturating_sub(sent.sent_at);
+        self.rtt = if self.rtt.is_zero() {
+            rtt
+        } else {
+            self.rtt.mul_f32(0.9) + rtt.mul_f32(0.1)
+        };
+
+        if let Some((channel, channel_sequence)) = sent.reliable {
+            // Could have already been delivered by an earlier copy
+            let delivered = self
+                .reliable_channel(channel)
+                .is_some_and(|reliable| reliable.pending.remove(&channel_sequence).is_some());
+
+            if delivered {
+                if let Some(on_delivered) = &mut self.on_delivered {
+                    on_delivered(channel, channel_sequence);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{
+        clock::ManualClock,
+        net::{LinkConditions, LossModel, Transport, UnreliableNetwork},
+        rng::SimRng,
+    };
+
+    fn lossy_network(seed: u64, clock: &ManualClock) -> Rc<RefCell<UnreliableNetwork>> {
+        let mut network = UnreliableNetwork::with_rng_and_clock(SimRng::new(seed), Rc::new(clock.clone()));
+        network.conditions = LinkConditions {
+            min_latency_ms: 20,
+            max_latency_ms: 80,
+            loss: LossModel::Uniform { drop_rate: 0.3 },
+            duplicate_rate: 0.05,
+            ..Default::default()
+        };
+        Rc::new(RefCell::new(network))
+    }
+
+    // Everything that has arrived in an inbox so far, through the connection
+    fn receive_all(network: &Rc<RefCell<UnreliableNetwork>>, connection: &mut Connection) -> Vec<Message> {
+        let mut received = Vec::new();
+        loop {
+            let Some((_, message)) = network.borrow_mut().receive() else {
+                break;
+            };
+            received.extend(connection.receive(message));
+        }
+        received
+    }
+
+    #[test]
+    fn reliable_messages_survive_a_lossy_link() {
+        const MESSAGES: i32 = 200;
+
+        let clock = ManualClock::new();
+        let shared_clock: SharedClock = Rc::new(clock.clone());
+        let client_inbox = lossy_network(1, &clock);
+        let server_inbox = lossy_network(2, &clock);
+
+        let mut client = Connection::with_clock(1, server_inbox.clone(), Rc::clone(&shared_clock));
+        let mut server = Connection::with_clock(0, client_inbox.clone(), shared_clock);
+
+        let delivered = Rc::new(RefCell::new(Vec::new()));
+        let on_delivered = Rc::clone(&delivered);
+        client.on_delivered(move |channel, sequence| on_delivered.borrow_mut().push((channel, sequence)));
+
+        let mut ordered = Vec::new();
+        let mut unordered = Vec::new();
+        // Keepalives are unreliable too, they look like message 0
+        let mut unreliable = HashSet::new();
+
+        for step in 0..2000 {
+            if step < MESSAGES {
+                let message = Message {
+                    sequence: step,
+                    ..Default::default()
+                };
+                client.send_on(Channel::ReliableOrdered, message.clone());
+                client.send_on(Channel::ReliableUnordered, message.clone());
+                client.send(message);
+            }
+            client.update();
+
+            for message in receive_all(&server_inbox, &mut server) {
+                match message.channel {
+                    Channel::ReliableOrdered => ordered.push(message.sequence),
+                    Channel::ReliableUnordered => unordered.push(message.sequence),
+                    Channel::Unreliable => {
+                        unreliable.insert(message.sequence);
+                    }
+                }
+            }
+
+            // The server only sends keepalives, acks ride on those
+            server.update();
+            receive_all(&client_inbox, &mut client);
+
+            if step >= MESSAGES && client.pending_reliable() == 0 {
+                break;
+            }
+            clock.advance(Duration::from_millis(16));
+        }
+
+        let expected: Vec<i32> = (0..MESSAGES).collect();
+        assert_eq!(ordered, expected, "ordered messages out of order or duplicated");
+
+        let mut unordered_sorted = unordered.clone();
+        unordered_sorted.sort();
+        assert_eq!(unordered_sorted, expected, "unordered messages missing or duplicated");
+
+        // Sanity check that the link really was losing things
+        assert!(unreliable.len() < MESSAGES as usize * 8 / 10, "not enough unreliable messages lost");
+
+        let delivered = delivered.borrow();
+        for channel in [Channel::ReliableOrdered, Channel::ReliableUnordered] {
+            let mut sequences: Vec<u16> = delivered
+                .iter()
+                .filter(|(delivered_channel, _)| *delivered_channel == channel)
+                .map(|(_, sequence)| *sequence)
+                .collect();
+            sequences.sort();
+            assert_eq!(sequences, (0..MESSAGES as u16).collect::<Vec<_>>(), "{:?} deliveries", channel);
+        }
+
+        assert_eq!(client.pending_reliable(), 0);
+    }
+}
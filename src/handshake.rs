@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use crate::sim::Colour;
+
+// How often a handshake message is sent again while we wait for an answer
+pub const HANDSHAKE_RESEND_INTERVAL: Duration = Duration::from_millis(100);
+
+// Disconnects are sent this many times in a row since nobody waits to resend them
+pub const DISCONNECT_REDUNDANCY: usize = 3;
+
+/// Messages for setting up and tearing down a connection.
+/// They're sent straight over the transport rather than a Connection, which
+/// only exists once both ends agree they're connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake {
+    /// Client asking to join, as the colour it wants to play as. With the
+    /// session it was given last time it connected, to carry on with it.
+    /// The nonce is new for every attempt at connecting, so a late or
+    /// duplicated request can be told apart from the client starting over
+    ConnectRequest {
+        colour: Colour,
+        session: Option<u32>,
+        nonce: u32,
+    },
+    /// Server checking the client can receive where it claims to be before
+    /// setting anything up for it, by having it send the salt back. Along
+    /// with the nonce of the request it's answering
+    Challenge { salt: u32, nonce: u32 },
+    ChallengeResponse { salt: u32 },
+    /// Client is in, controlling the given server entity. The session
+    /// lets it pick up where it left off if it drops and connects again
+    Accept { entity_id: i32, session: u32 },
+    /// Client isn't getting in
+    Deny { reason: DenyReason },
+    /// Either end is leaving
+    Disconnect,
+}
+
+/// Why the server turned a client away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    /// Already at max_clients
+    ServerFull,
+    /// Answered a challenge the server doesn't have, e.g. one it gave up
+    /// waiting on. The client starts over with a new request
+    ChallengeExpired,
+}
+
+/// Why a client was dropped by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client said it was leaving
+    Left,
+    /// Nothing heard from the client for the server's client_timeout
+    TimedOut,
+    /// The server told it to leave
+    Kicked,
+}
+
+/// Where a client is in connecting to the server
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    /// Sending connect requests, waiting for a challenge
+    Requesting,
+    /// Sending the challenge back, waiting to be accepted
+    Challenged { salt: u32 },
+    Connected,
+    /// Still connected but nothing has arrived from the server for a while
+    Interrupted,
+    /// The server said no, we won't try again until told to
+    Denied(DenyReason),
+    /// The server went quiet for longer than our timeout and we gave up on it
+    TimedOut,
+}
@@ -0,0 +1,154 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::sim::World;
+
+/// Position of every entity by id
+pub type Positions = HashMap<i32, (f32, f32)>;
+
+/// Where every entity was over the last few ticks, so the server can look at the
+/// world the way a client saw it when they acted rather than how it is now
+#[derive(Default)]
+pub struct WorldHistory {
+    // Entity positions by tick, oldest first
+    ticks: VecDeque<(i32, Positions)>,
+}
+
+impl WorldHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Remember the world as it is at this tick, keeping at most max_ticks
+    pub fn record(&mut self, tick: i32, world: &World, max_ticks: usize) {
+        let positions = world
+            .get_entities()
+            .iter()
+            .map(|(entity_id, entity)| (*entity_id, entity.position))
+            .collect();
+
+        self.ticks.push_back((tick, positions));
+        while self.ticks.len() > max_ticks.max(1) {
+            self.ticks.pop_front();
+        }
+    }
+
+    pub fn newest_tick(&self) -> Option<i32> {
+        self.ticks.back().map(|(tick, _)| *tick)
+    }
+
+    pub fn oldest_tick(&self) -> Option<i32> {
+        self.ticks.front().map(|(tick, _)| *tick)
+    }
+
+    // Every entity as it was at a tick, which can be part way between two as
+    // clients draw other entities blended between snapshots.
+    // Anything outside of what we remember is clamped to the nearest end.
+    // Entities gone from the world since are left out, there's nothing left to hit
+    pub fn rewind(&self, view_tick: f32, world: &World) -> Option<Positions> {
+        let oldest = self.oldest_tick()? as f32;
+        let newest = self.newest_tick()? as f32;
+        let view_tick = view_tick.clamp(oldest, newest);
+
+        // The recorded ticks either side of the view tick
+        let after = self
+            .ticks
+            .partition_point(|(tick, _)| (*tick as f32) < view_tick)
+            .min(self.ticks.len() - 1);
+        let before = if self.ticks[after].0 as f32 > view_tick { after - 1 } else { after };
+
+        let (before_tick, before_positions) = &self.ticks[before];
+        let (after_tick, after_positions) = &self.ticks[after];
+        let exists = |entity_id: &i32| world.get_entities().contains_key(entity_id);
+        if before == after {
+            let mut positions = before_positions.clone();
+            positions.retain(|entity_id, _| exists(entity_id));
+            return Some(positions);
+        }
+
+        let lerp_fac = (view_tick - *before_tick as f32) / (after_tick - before_tick) as f32;
+
+        // Only entities that existed at both ends, anything spawned or removed
+        // in between uses whichever end it was there for
+        let mut positions = before_positions.clone();
+        for (entity_id, (x1, y1)) in after_positions {
+            let position = match before_positions.get(entity_id) {
+                Some((x0, y0)) => (x0 + (x1 - x0) * lerp_fac, y0 + (y1 - y0) * lerp_fac),
+                None => (*x1, *y1),
+            };
+            positions.insert(*entity_id, position);
+        }
+        positions.retain(|entity_id, _| exists(entity_id));
+
+        Some(positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Entity;
+
+    fn add_at(world: &mut World, position: (f32, f32)) -> i32 {
+        let mut entity = Entity::new();
+        entity.position = position;
+        world.add_entity(entity)
+    }
+
+    fn move_to(world: &mut World, entity_id: i32, position: (f32, f32)) {
+        world.get_entities_mut().get_mut(&entity_id).unwrap().position = position;
+    }
+
+    #[test]
+    fn rewinds_to_recorded_and_in_between_ticks() {
+        let mut world = World::new();
+        let entity_id = add_at(&mut world, (0.0, 0.0));
+        let mut history = WorldHistory::new();
+        history.record(10, &world, 8);
+        move_to(&mut world, entity_id, (10.0, 20.0));
+        history.record(11, &world, 8);
+
+        assert_eq!(history.rewind(10.0, &world).unwrap()[&entity_id], (0.0, 0.0));
+        assert_eq!(history.rewind(11.0, &world).unwrap()[&entity_id], (10.0, 20.0));
+        assert_eq!(history.rewind(10.5, &world).unwrap()[&entity_id], (5.0, 10.0));
+    }
+
+    #[test]
+    fn forgets_ticks_past_the_limit() {
+        let mut world = World::new();
+        let entity_id = add_at(&mut world, (0.0, 0.0));
+        let mut history = WorldHistory::new();
+        for tick in 0..5 {
+            move_to(&mut world, entity_id, (tick as f32, 0.0));
+            history.record(tick, &world, 3);
+        }
+
+        assert_eq!(history.oldest_tick(), Some(2));
+        assert_eq!(history.newest_tick(), Some(4));
+        // Further back than we remember is as far back as we can go
+        assert_eq!(history.rewind(0.0, &world).unwrap()[&entity_id], (2.0, 0.0));
+        assert_eq!(history.rewind(9.0, &world).unwrap()[&entity_id], (4.0, 0.0));
+    }
+
+    #[test]
+    fn leaves_out_entities_despawned_since() {
+        let mut world = World::new();
+        let survivor = add_at(&mut world, (0.0, 0.0));
+        let despawned = add_at(&mut world, (50.0, 0.0));
+        let mut history = WorldHistory::new();
+        history.record(0, &world, 8);
+        history.record(1, &world, 8);
+
+        world.remove_entity(despawned);
+
+        for view_tick in [0.0, 0.5, 1.0] {
+            let positions = history.rewind(view_tick, &world).unwrap();
+            assert!(positions.contains_key(&survivor));
+            assert!(!positions.contains_key(&despawned));
+        }
+    }
+
+    #[test]
+    fn nothing_to_rewind_to_before_recording() {
+        assert_eq!(WorldHistory::new().rewind(0.0, &World::new()), None);
+    }
+}
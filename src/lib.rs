@@ -0,0 +1,15 @@
+pub mod bits;
+pub mod client;
+pub mod clock;
+pub mod clocksync;
+pub mod combat;
+pub mod connection;
+pub mod handshake;
+pub mod lagcompensation;
+pub mod net;
+pub mod rng;
+pub mod server;
+pub mod sim;
+pub mod ticktimer;
+pub mod udp;
+pub mod wire;
@@ -1,14 +1,9 @@
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
 use macroquad::input::{is_key_pressed, KeyCode};
 
-use client::Client;
+use gamenetworking::{client::Client, combat::{Tracer, Weapon}, handshake::{ConnectionState, DisconnectReason}, net::{LinkConditions, LossModel}, server, sim::{self, Entity, Projectile, ENTITY_SIZE, MAX_HEALTH}, udp::UdpTransport};
 use macroquad::{prelude::*, ui::*};
-use sim::Entity;
-
-mod client;
-mod net;
-mod server;
-mod sim;
-mod ticktimer;
 
 fn create_grid_camera(width: f32, height: f32) -> Camera2D {
     let rect = Rect::new(0., 0., width, height);
@@ -20,7 +15,7 @@ fn create_grid_camera(width: f32, height: f32) -> Camera2D {
     }
 }
 
-fn draw_client(client: &Client) {
+fn draw_client(client: &Client, server: &server::Server) {
     draw_text(
         format!("Client {}", client.get_id()).as_str(),
         20.,
@@ -29,15 +24,19 @@ fn draw_client(client: &Client) {
         WHITE,
     );
 
-    if !client.connected {
-        draw_text(
-            format!("Press {} to connect", client.get_id()).as_str(),
-            20.,
-            40.,
-            16.,
-            WHITE,
-        );
-    }
+    // Draw how connecting is going
+    let status = match client.state {
+        ConnectionState::Disconnected if client.session().is_some() => {
+            format!("Press {} to reconnect", client.get_id())
+        }
+        ConnectionState::Disconnected => format!("Press {} to connect", client.get_id()),
+        ConnectionState::Requesting | ConnectionState::Challenged { .. } => String::from("Connecting..."),
+        ConnectionState::Connected => format!("Press {} to disconnect", client.get_id() + 2),
+        ConnectionState::Interrupted => String::from("Connection interrupted..."),
+        ConnectionState::TimedOut => format!("Timed out, press {} to reconnect", client.get_id()),
+        ConnectionState::Denied(reason) => format!("Denied ({:?}), press {} to try again", reason, client.get_id()),
+    };
+    draw_text(status.as_str(), 20., 40., 16., WHITE);
 
     // Draw tick rate
     draw_text(
@@ -55,26 +54,92 @@ fn draw_client(client: &Client) {
         16.,
         WHITE,
     );
-    // Draw latency info
+    // Draw latency info for each direction
+    let upstream = server.get_network().borrow().sender_conditions(client.get_id());
+    let downstream = client.network.borrow().conditions();
     draw_text(
-        format!("Min Latency: {}ms", client.network.borrow().min_latency_ms).as_str(),
+        format!("Upstream Latency: {}-{}ms", upstream.min_latency_ms, upstream.max_latency_ms).as_str(),
         20.,
         100.,
         16.,
         WHITE,
     );
     draw_text(
-        format!("Max Latency: {}ms", client.network.borrow().max_latency_ms).as_str(),
+        format!("Downstream Latency: {}-{}ms", downstream.min_latency_ms, downstream.max_latency_ms).as_str(),
         20.,
         120.,
         16.,
         WHITE,
     );
+    draw_text(
+        format!("Queue Delay: {}ms", client.network.borrow().queue_delay().as_millis()).as_str(),
+        20.,
+        140.,
+        16.,
+        WHITE,
+    );
+    // Draw how early our input reaches the server
+    if let (Some(input_lead), Some(timing)) = (client.input_lead, server.input_timing(client.get_id())) {
+        draw_text(
+            format!(
+                "Input Lead: {:.1}/{:.1} ticks, Buffered: {}, Dilation: {:.3}",
+                input_lead,
+                client.target_input_lead,
+                server.input_buffer_len(client.get_id()),
+                client.tick_timer.dilation
+            )
+            .as_str(),
+            20.,
+            180.,
+            16.,
+            WHITE,
+        );
+        draw_text(
+            format!("Late Input: {:.1}%, Missing Input: {}", timing.late_rate() * 100.0, timing.missing).as_str(),
+            20.,
+            200.,
+            16.,
+            WHITE,
+        );
+    }
+    // Draw how far ahead of the server we're running
+    if let Some(server_tick) = client.estimated_server_tick() {
+        draw_text(
+            format!(
+                "RTT: {}ms, Ticks Ahead: {:.1}",
+                client.clock_sync.rtt.as_millis(),
+                client.tick_timer.current_tick as f64 - server_tick
+            )
+            .as_str(),
+            20.,
+            160.,
+            16.,
+            WHITE,
+        );
+    }
+
+    // Draw the latest kills, newest at the top
+    for (line, (shooter, target)) in client.kill_feed.iter().rev().enumerate() {
+        let colour_of = |entity_id| {
+            client.world.get_entities().get(entity_id).map_or(String::from("?"), |entity: &Entity| {
+                format!("{:?}", entity.colour)
+            })
+        };
+        draw_text(
+            format!("{} killed {}", colour_of(shooter), colour_of(target)).as_str(),
+            20.,
+            220. + line as f32 * 20.,
+            16.,
+            WHITE,
+        );
+    }
 
-    draw_entities(client.world.get_entities().values().collect());
+    draw_entities(client.render_entities().iter().collect());
+    draw_projectiles(client.world.get_projectiles().values().collect());
+    draw_tracers(&client.tracers);
 }
 
-fn draw_server(server: &server::Server) {
+fn draw_server(server: &server::Server, last_disconnect: Option<(i32, DisconnectReason)>) {
     draw_text("Server", 20., 20., 32., WHITE);
 
     // Draw tick rate
@@ -85,8 +150,26 @@ fn draw_server(server: &server::Server) {
         16.,
         WHITE,
     );
+    draw_text(
+        format!("Snapshot Size: {} bytes across all clients", server.snapshot_bytes).as_str(),
+        20.,
+        80.,
+        16.,
+        WHITE,
+    );
+    draw_text("Press N to add an npc, M to remove one", 20., 100., 16., WHITE);
+    if let Some((client_id, reason)) = last_disconnect {
+        draw_text(
+            format!("Client {} disconnected: {:?}", client_id, reason).as_str(),
+            20.,
+            120.,
+            16.,
+            WHITE,
+        );
+    }
 
     draw_entities(server.world.get_entities().values().collect());
+    draw_projectiles(server.world.get_projectiles().values().collect());
 }
 
 fn draw_entities(entities: Vec<&Entity>) {
@@ -100,10 +183,27 @@ fn draw_entities(entities: Vec<&Entity>) {
         draw_rectangle(
             entity.position.0,
             entity.position.1,
-            50.,
-            50.,
+            ENTITY_SIZE,
+            ENTITY_SIZE,
             macroquad_colour,
         );
+
+        // Health bar just above
+        let health = entity.health.clamp(0, MAX_HEALTH) as f32 / MAX_HEALTH as f32;
+        draw_rectangle(entity.position.0, entity.position.1 - 8., ENTITY_SIZE, 4., DARKGRAY);
+        draw_rectangle(entity.position.0, entity.position.1 - 8., ENTITY_SIZE * health, 4., GREEN);
+    }
+}
+
+fn draw_projectiles(projectiles: Vec<&Projectile>) {
+    for projectile in projectiles {
+        draw_circle(projectile.position.0, projectile.position.1, 4., BLACK);
+    }
+}
+
+fn draw_tracers(tracers: &[Tracer]) {
+    for tracer in tracers {
+        draw_line(tracer.from.0, tracer.from.1, tracer.to.0, tracer.to.1, 2., YELLOW);
     }
 }
 
@@ -141,7 +241,7 @@ fn draw_ui(ui_state: &mut UIState) {
     if ui_state.open_settings {
         widgets::Window::new(hash!(), vec2(screen_width() / 2., 50.), vec2(300., 300.))
             .label("Settings")
-            .ui(&mut *root_ui(), |ui| {
+            .ui(&mut root_ui(), |ui| {
                 ui.label(None, "Client 1");
                 widgets::Checkbox::new(hash!())
                     .label("Prediction")
@@ -155,6 +255,14 @@ fn draw_ui(ui_state: &mut UIState) {
                     .label("Extrapolation")
                     .ratio(0.2)
                     .ui(ui, &mut ui_state.client_1_extrapolation);
+                widgets::Checkbox::new(hash!())
+                    .label("Burst Loss")
+                    .ratio(0.2)
+                    .ui(ui, &mut ui_state.client_1_burst_loss);
+                widgets::Checkbox::new(hash!())
+                    .label("Bandwidth Cap")
+                    .ratio(0.2)
+                    .ui(ui, &mut ui_state.client_1_bandwidth_cap);
                 ui.separator();
                 ui.label(None, "Client 2");
                 widgets::Checkbox::new(hash!())
@@ -169,25 +277,114 @@ fn draw_ui(ui_state: &mut UIState) {
                     .label("Extrapolation")
                     .ratio(0.2)
                     .ui(ui, &mut ui_state.client_2_extrapolation);
+                widgets::Checkbox::new(hash!())
+                    .label("Burst Loss")
+                    .ratio(0.2)
+                    .ui(ui, &mut ui_state.client_2_burst_loss);
+                widgets::Checkbox::new(hash!())
+                    .label("Bandwidth Cap")
+                    .ratio(0.2)
+                    .ui(ui, &mut ui_state.client_2_bandwidth_cap);
+                ui.separator();
+                ui.label(None, "Server");
+                widgets::Checkbox::new(hash!())
+                    .label("Delta Compression")
+                    .ratio(0.2)
+                    .ui(ui, &mut ui_state.server_delta_compression);
+                widgets::Checkbox::new(hash!())
+                    .label("Repeat Missing Input")
+                    .ratio(0.2)
+                    .ui(ui, &mut ui_state.server_repeat_missing_input);
+                widgets::Checkbox::new(hash!())
+                    .label("Projectile Weapon")
+                    .ratio(0.2)
+                    .ui(ui, &mut ui_state.server_projectile_weapon);
             });
     }
 }
 
+// Applies the settings for the link carrying snapshots to the client.
+// Burst loss drops 5% of them averaging 5 in a row, the bandwidth cap
+// is small enough that adding npcs soon fills it
+fn set_client_link(client: &Client, burst_loss: bool, bandwidth_cap: bool) {
+    let mut network = client.network.borrow_mut();
+    let mut conditions = network.conditions();
+    conditions.loss = if burst_loss {
+        LossModel::burst(0.05, 5.0)
+    } else {
+        LossModel::default()
+    };
+    (conditions.bandwidth_bytes_per_sec, conditions.send_queue_bytes) = if bandwidth_cap {
+        (2000, 4000)
+    } else {
+        (0, 0)
+    };
+    network.set_conditions(conditions);
+}
+
+// Connects a client over the fake network, or over real UDP sockets
+// on loopback when running with --udp
+fn connect_client(
+    client: &mut Client,
+    server: &mut server::Server,
+    latency_ms: u64,
+    sockets: Option<(&UdpTransport, &UdpTransport)>,
+) {
+    match sockets {
+        // The server learns where we are from the packets we send it
+        Some((server_socket, client_socket)) => {
+            let to_server = client_socket.to(server_socket.remote_addr());
+            client.connect_to(Rc::new(RefCell::new(to_server)));
+        }
+        None => {
+            let conditions = LinkConditions {
+                min_latency_ms: latency_ms,
+                max_latency_ms: latency_ms,
+                ..Default::default()
+            };
+            client.connect(server, conditions, conditions);
+        }
+    }
+}
+
 struct UIState {
     open_settings: bool,
     client_1_prediction: bool,
     client_1_reconciliation: bool,
     client_1_extrapolation: bool,
+    client_1_burst_loss: bool,
+    client_1_bandwidth_cap: bool,
     client_2_prediction: bool,
     client_2_reconciliation: bool,
     client_2_extrapolation: bool,
+    client_2_burst_loss: bool,
+    client_2_bandwidth_cap: bool,
+    server_delta_compression: bool,
+    server_repeat_missing_input: bool,
+    server_projectile_weapon: bool,
 }
 
 #[macroquad::main("Fast GameNetworking Example")]
 async fn main() {
-    let mut server = server::Server::new(50);
-    let mut client1 = Client::new(1, 16);
-    let mut client2 = Client::new(2, 16);
+    // Run the same demo over real UDP sockets rather than the fake network
+    let use_udp = std::env::args().any(|arg| arg == "--udp");
+    let sockets = if use_udp {
+        let bind = || UdpTransport::bind("127.0.0.1:0").expect("failed to bind UDP socket");
+        Some((bind(), bind(), bind()))
+    } else {
+        None
+    };
+
+    let (mut server, mut client1, mut client2) = match &sockets {
+        Some((server_socket, client1_socket, client2_socket)) => (
+            server::Server::with_network(50, Rc::new(RefCell::new(server_socket.clone()))),
+            Client::with_network(1, 16, Rc::new(RefCell::new(client1_socket.clone()))),
+            Client::with_network(2, 16, Rc::new(RefCell::new(client2_socket.clone()))),
+        ),
+        None => (server::Server::new(50), Client::new(1, 16), Client::new(2, 16)),
+    };
+    let client1_sockets = sockets.as_ref().map(|(server, client1, _)| (server, client1));
+    let client2_sockets = sockets.as_ref().map(|(server, _, client2)| (server, client2));
 
     // This is just for helping us identify which is which
     client1.colour = sim::Colour::Red;
@@ -201,29 +398,59 @@ async fn main() {
         client_1_prediction: true,
         client_1_reconciliation: true,
         client_1_extrapolation: true,
+        client_1_burst_loss: false,
+        client_1_bandwidth_cap: false,
         client_2_prediction: true,
         client_2_reconciliation: true,
         client_2_extrapolation: true,
+        client_2_burst_loss: false,
+        client_2_bandwidth_cap: false,
+        server_delta_compression: true,
+        server_repeat_missing_input: false,
+        server_projectile_weapon: false,
     };
 
     let mut pause_client_1 = false;
 
-    client1.connect(&mut server, 250, 250, 0.);
-    client2.connect(&mut server, 100, 100, 0.);
+    connect_client(&mut client1, &mut server, 250, client1_sockets);
+    connect_client(&mut client2, &mut server, 100, client2_sockets);
 
     server.create_npc_entities();
 
+    // Shown on the server panel, e.g. client 1 timing out while paused
+    let last_disconnect = Rc::new(Cell::new(None));
+    server.on_disconnect({
+        let last_disconnect = Rc::clone(&last_disconnect);
+        move |client_id, reason| last_disconnect.set(Some((client_id, reason)))
+    });
+
     loop {
         let grid_section_width = screen_width() / 2.;
         let grid_section_height = screen_height() / 2.;
 
         // On press 1, connect client 1
         if is_key_pressed(KeyCode::Key1) {
-            client1.connect(&mut server, 250, 250, 0.);
+            connect_client(&mut client1, &mut server, 250, client1_sockets);
         }
         // On press 2, connect client 2
         if is_key_pressed(KeyCode::Key2) {
-            client2.connect(&mut server, 100, 100, 0.);
+            connect_client(&mut client2, &mut server, 100, client2_sockets);
+        }
+        // On press 3 or 4, disconnect client 1 or 2
+        if is_key_pressed(KeyCode::Key3) {
+            client1.disconnect();
+        }
+        if is_key_pressed(KeyCode::Key4) {
+            client2.disconnect();
+        }
+
+        // On press N, add another npc to grow the snapshots
+        if is_key_pressed(KeyCode::N) {
+            server.create_npc_entities();
+        }
+
+        if is_key_pressed(KeyCode::M) {
+            server.remove_npc_entity();
         }
 
         if is_key_pressed(KeyCode::P) {
@@ -235,25 +462,38 @@ async fn main() {
             client1.client_prediction_enabled = ui_state.client_1_prediction;
             client1.server_reconciliation_enabled = ui_state.client_1_reconciliation;
             client1.extrapolation_enabled = ui_state.client_1_extrapolation;
+            set_client_link(&client1, ui_state.client_1_burst_loss, ui_state.client_1_bandwidth_cap);
         }
 
         client2.update();
         client2.client_prediction_enabled = ui_state.client_2_prediction;
         client2.server_reconciliation_enabled = ui_state.client_2_reconciliation;
         client2.extrapolation_enabled = ui_state.client_2_extrapolation;
+        set_client_link(&client2, ui_state.client_2_burst_loss, ui_state.client_2_bandwidth_cap);
 
+        server.delta_compression_enabled = ui_state.server_delta_compression;
+        server.missing_input = if ui_state.server_repeat_missing_input {
+            server::MissingInput::RepeatLast
+        } else {
+            server::MissingInput::Idle
+        };
+        server.weapon = if ui_state.server_projectile_weapon {
+            Weapon::Projectile
+        } else {
+            Weapon::Hitscan
+        };
         server.update();
 
         clear_background(LIGHTGRAY);
 
         draw_top_left(grid_section_width, grid_section_height);
-        draw_client(&client1);
+        draw_client(&client1, &server);
 
         draw_top_right(grid_section_width, grid_section_height);
-        draw_client(&client2);
+        draw_client(&client2, &server);
 
         draw_bottom_left(grid_section_width, grid_section_height);
-        draw_server(&server);
+        draw_server(&server, last_disconnect.get());
 
         draw_bottom_right(grid_section_width, grid_section_height);
 
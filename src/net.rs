@@ -1,56 +1,382 @@
-use std::{collections::VecDeque, time::{Duration, Instant}};
+use std::{cell::RefCell, collections::{HashMap, VecDeque}, rc::Rc, time::Duration};
 
-use macroquad::rand;
+use crate::{
+    clock::{SharedClock, SystemClock},
+    combat::CombatEvent,
+    handshake::Handshake,
+    rng::SimRng,
+    sim::{Colour, Input},
+    wire,
+};
 
-use crate::sim::Colour;
+// IPv4 and UDP headers that every datagram would carry on a real link
+const PACKET_OVERHEAD_BYTES: u64 = 28;
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct Message {
     pub sequence: i32,
     pub state: Option<Vec<State>>,
-    pub input: Option<(bool, bool, bool, bool)>,
+    pub input: Option<Input>,
+    // Server tick the state or delta in this message was taken at
+    pub snapshot_tick: Option<i32>,
+    // World state relative to a snapshot the client already has
+    pub delta: Option<Delta>,
+    // Latest snapshot tick the client has received, the server deltas against it
+    pub snapshot_ack: Option<i32>,
+    // Filled in by a Connection for acking and resending
+    pub packet: Option<PacketHeader>,
+    // Which channel of the connection this was sent on
+    pub channel: Channel,
+    // Sequence on a reliable channel, for spotting resent copies and ordering
+    pub channel_sequence: Option<u16>,
+    // Client clock time in ms, the server echoes it back in a pong
+    pub ping: Option<u32>,
+    // The servers reply to a ping
+    pub pong: Option<Pong>,
+    // How many ticks ahead of the server the clients latest input arrived, negative if late
+    pub input_lead: Option<i32>,
+    // Earlier inputs the server hasn't acknowledged yet, with their sequences.
+    // Sent again with every new input so one lost packet doesn't lose a move
+    pub previous_inputs: Vec<(i32, Input)>,
+    // Server tick the client was drawing other entities at when it made the input
+    pub view_tick: Option<f32>,
+    // Hits, kills and shots since the last message, sent reliably
+    pub events: Vec<CombatEvent>,
+    // Entities the server has removed, sent reliably so clients can drop them too
+    pub despawns: Vec<i32>,
+    // Connecting and disconnecting, these are sent outside of a Connection
+    pub handshake: Option<Handshake>,
 }
 
+/// Reply to a ping, saying where the server was when it answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pong {
+    // The ping time being echoed back
+    pub ping_time: u32,
+    // Tick the server was on when it replied
+    pub server_tick: i32,
+    pub tick_interval_ms: u32,
+}
 
-#[derive(Default, Debug, Clone, Copy)]
+/// How a Connection delivers a message
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    // Sent once, might not arrive, e.g. snapshots where only the newest matters
+    #[default]
+    Unreliable,
+    // Resent until acked, processed as soon as it arrives
+    ReliableUnordered,
+    // Resent until acked, processed in the order it was sent
+    ReliableOrdered,
+}
+
+/// Sequencing and acks for a packet sent over a Connection
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct PacketHeader {
+    pub sequence: u16,
+    // Newest sequence received from the other end, None until we've had one
+    pub ack: Option<u16>,
+    // Bit n set means ack - (n + 1) was also received
+    pub ack_bits: u32,
+}
+
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
 pub struct State {
     pub entity_id: i32,
     pub position: (f32, f32),
     pub colour: Colour,
 }
 
+/// Fields of an entity that changed since the baseline, unchanged fields are None
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct StateDelta {
+    pub entity_id: i32,
+    pub position: Option<(f32, f32)>,
+    pub colour: Option<Colour>,
+}
+
+/// Changes to go from the baseline snapshot to a newer one
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Delta {
+    pub baseline_tick: i32,
+    pub changed: Vec<StateDelta>,
+    // Entities in the baseline that no longer exist
+    pub removed: Vec<i32>,
+}
+
+impl Delta {
+    // Work out what changed between the baseline and the current states
+    pub fn between(baseline_tick: i32, baseline: &HashMap<i32, State>, current: &[State]) -> Self {
+        let mut changed = Vec::new();
+        for state in current {
+            let previous = baseline.get(&state.entity_id);
+
+            // Entities new since the baseline get every field
+            let position = match previous {
+                Some(previous) if previous.position == state.position => None,
+                _ => Some(state.position),
+            };
+            let colour = match previous {
+                Some(previous) if previous.colour == state.colour => None,
+                _ => Some(state.colour),
+            };
+
+            if position.is_some() || colour.is_some() {
+                changed.push(StateDelta {
+                    entity_id: state.entity_id,
+                    position,
+                    colour,
+                });
+            }
+        }
+
+        let removed = baseline
+            .keys()
+            .filter(|entity_id| !current.iter().any(|state| state.entity_id == **entity_id))
+            .copied()
+            .collect();
+
+        Delta {
+            baseline_tick,
+            changed,
+            removed,
+        }
+    }
+
+    // Rebuild the full states from the baseline this delta was made against
+    pub fn apply(&self, baseline: &HashMap<i32, State>) -> HashMap<i32, State> {
+        let mut states = baseline.clone();
+
+        for delta in &self.changed {
+            let state = states.entry(delta.entity_id).or_insert(State {
+                entity_id: delta.entity_id,
+                ..Default::default()
+            });
+            if let Some(position) = delta.position {
+                state.position = position;
+            }
+            if let Some(colour) = delta.colour {
+                state.colour = colour;
+            }
+        }
+
+        for entity_id in &self.removed {
+            states.remove(entity_id);
+        }
+
+        states
+    }
+}
+
+/// Latency and loss applied by a simulated link
+#[derive(Default, Debug, Clone, Copy)]
+pub struct LinkConditions {
+    pub min_latency_ms: u64,
+    pub max_latency_ms: u64,
+    // How latency is spread between min and max
+    pub jitter: Jitter,
+    // Which messages never arrive
+    pub loss: LossModel,
+    // Chance a message arrives twice, each copy with its own latency
+    pub duplicate_rate: f32,
+    // Chance a message is held back by reorder_delay_ms so later ones overtake it
+    pub reorder_rate: f32,
+    pub reorder_delay_ms: u64,
+    // Throughput of the link, 0 for unlimited. Messages wait their turn to be sent
+    pub bandwidth_bytes_per_sec: u64,
+    // Bytes that can be waiting to send before new messages are dropped, 0 for unlimited
+    pub send_queue_bytes: u64,
+}
+
+/// Distribution latency is picked from, always kept within min and max latency
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum Jitter {
+    // Any latency between min and max is as likely as any other
+    #[default]
+    Uniform,
+    // Bunched around the middle of min and max
+    Normal { std_dev_ms: f32 },
+}
+
+/// How a simulated link decides to drop messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LossModel {
+    // Every message has the same independent chance of being dropped
+    Uniform { drop_rate: f32 },
+    // Gilbert-Elliott two state model. The link flips between a good and a bad
+    // state before each message and drops with the rate for the state it is in,
+    // so losses come in bursts like they do on wifi or mobile
+    GilbertElliott {
+        // Chance of going from good to bad, and back again
+        good_to_bad: f32,
+        bad_to_good: f32,
+        good_drop_rate: f32,
+        bad_drop_rate: f32,
+    },
+}
+
+impl Default for LossModel {
+    fn default() -> Self {
+        LossModel::Uniform { drop_rate: 0.0 }
+    }
+}
+
+impl LossModel {
+    // Bursts where everything is lost, averaging burst_length messages long,
+    // that add up to drop_rate of all messages
+    pub fn burst(drop_rate: f32, burst_length: f32) -> Self {
+        let drop_rate = drop_rate.clamp(0.0, 0.99);
+        let bad_to_good = 1.0 / burst_length.max(1.0);
+
+        LossModel::GilbertElliott {
+            good_to_bad: drop_rate * bad_to_good / (1.0 - drop_rate),
+            bad_to_good,
+            good_drop_rate: 0.0,
+            bad_drop_rate: 1.0,
+        }
+    }
+
+    // Decide if the next message is dropped, moving the link between states
+    fn should_drop(&self, rng: &mut SimRng, in_bad_state: &mut bool) -> bool {
+        match *self {
+            LossModel::Uniform { drop_rate } => rng.chance(drop_rate),
+            LossModel::GilbertElliott {
+                good_to_bad,
+                bad_to_good,
+                good_drop_rate,
+                bad_drop_rate,
+            } => {
+                let transition = if *in_bad_state { bad_to_good } else { good_to_bad };
+                if rng.chance(transition) {
+                    *in_bad_state = !*in_bad_state;
+                }
+
+                let drop_rate = if *in_bad_state { bad_drop_rate } else { good_drop_rate };
+                rng.chance(drop_rate)
+            }
+        }
+    }
+}
+
+impl LinkConditions {
+    // Pick a latency for one message
+    fn sample_latency_ms(&self, rng: &mut SimRng) -> u64 {
+        let min = self.min_latency_ms.min(self.max_latency_ms);
+        let max = self.max_latency_ms.max(self.min_latency_ms);
+
+        match self.jitter {
+            Jitter::Uniform => rng.range(min, max),
+            Jitter::Normal { std_dev_ms } => {
+                // Box-Muller transform from two uniform samples
+                let u1 = rng.next_f32().max(f32::EPSILON);
+                let u2 = rng.next_f32();
+                let z = (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos();
+
+                let mean = (min + max) as f32 / 2.0;
+                ((mean + z * std_dev_ms).round() as u64).clamp(min, max)
+            }
+        }
+    }
+}
+
+/// The inbox of a network endpoint.
+/// Anyone holding a transport can send messages into it tagged with their own id,
+/// the owner of the transport receives them along with who sent them.
+pub trait Transport {
+    // Send a message along with who sent it
+    fn send(&mut self, sender_id: i32, message: Message);
+
+    // Returns the next message along with sender_id who sent the message
+    fn receive(&mut self) -> Option<(i32, Message)>;
+
+    // Called once per update before receiving so the transport can do any
+    // housekeeping, e.g. draining a socket
+    fn poll(&mut self) {}
+
+    // Handle for sending back to whoever sent us messages as sender_id, for
+    // transports that know where messages come from like a socket does.
+    // Simulated ones don't, so the server has to be told with add_route
+    fn reply_to(&self, _sender_id: i32) -> Option<SharedTransport> {
+        None
+    }
+
+    // The last message received as sender_id proved who sent it, e.g. by
+    // answering a challenge, so the id belongs to wherever it came from now
+    fn bind_sender(&mut self, _sender_id: i32) {}
+
+    // Simulated transports can report and change their link conditions,
+    // real ones just ignore this
+    fn conditions(&self) -> LinkConditions {
+        LinkConditions::default()
+    }
+
+    fn set_conditions(&mut self, _conditions: LinkConditions) {}
+
+    // Conditions for messages from one sender, so every direction between
+    // two endpoints can be its own link
+    fn sender_conditions(&self, _sender_id: i32) -> LinkConditions {
+        self.conditions()
+    }
+
+    fn set_sender_conditions(&mut self, _sender_id: i32, _conditions: LinkConditions) {}
+
+    // How long a message sent now would wait for the link to be free
+    fn queue_delay(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Transport shared between the owner and everyone sending to it
+pub type SharedTransport = Rc<RefCell<dyn Transport>>;
+
 pub struct ReliableOrderedNetwork {
     messages: VecDeque<(Duration, i32, Message)>,
-    timer: Instant,
+    rng: SimRng,
+    clock: SharedClock,
     pub min_latency_ms: u64,
     pub max_latency_ms: u64,
 }
 
 impl ReliableOrderedNetwork {
     pub fn new() -> Self {
+        Self::with_rng_and_clock(SimRng::from_time(), Rc::new(SystemClock::new()))
+    }
+
+    // Same rng seed and clock gives the same latencies every run
+    pub fn with_rng_and_clock(rng: SimRng, clock: SharedClock) -> Self {
         ReliableOrderedNetwork {
             messages: VecDeque::new(),
-            timer: Instant::now(),
+            rng,
+            clock,
             min_latency_ms: 0,
             max_latency_ms: 0,
         }
     }
+}
 
+impl Default for ReliableOrderedNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for ReliableOrderedNetwork {
     // Send a message along with who sent it
-    pub fn send(&mut self, sender_id: i32, message: Message) {
+    fn send(&mut self, sender_id: i32, message: Message) {
         // Simulate latency between two random values
-        let latency = rand::gen_range(self.min_latency_ms, self.max_latency_ms);
-        let delay = self.timer.elapsed() + Duration::from_millis(latency);
+        let latency = self.rng.range(self.min_latency_ms, self.max_latency_ms);
+        let delay = self.clock.now() + Duration::from_millis(latency);
 
         self.messages.push_back((delay, sender_id, message));
 
     }
 
     // Returns the next message along with sender_id who sent the message
-    pub fn receive(&mut self) -> Option<(i32, Message)> {
+    fn receive(&mut self) -> Option<(i32, Message)> {
         if let Some((delay, sender_id, message)) = self.messages.pop_front() {
             // If the delay has passed, we return the message
-            if delay <= self.timer.elapsed() {
+            if delay <= self.clock.now() {
                 return Some((sender_id, message));
             }
 
@@ -59,55 +385,284 @@ impl ReliableOrderedNetwork {
         }
         None
     }
+
+    fn conditions(&self) -> LinkConditions {
+        LinkConditions {
+            min_latency_ms: self.min_latency_ms,
+            max_latency_ms: self.max_latency_ms,
+            ..Default::default()
+        }
+    }
+
+    // Nothing is ever dropped so the drop rate is ignored
+    fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.min_latency_ms = conditions.min_latency_ms;
+        self.max_latency_ms = conditions.max_latency_ms;
+    }
+}
+
+// The link from one sender into an UnreliableNetwork
+#[derive(Default)]
+struct Link {
+    // Conditions just for this sender, otherwise the networks conditions apply
+    conditions: Option<LinkConditions>,
+    // Which state the link is in for the Gilbert-Elliott loss model
+    in_bad_state: bool,
+    // When the link will have finished sending everything queued so far
+    link_free_at: Duration,
+}
+
+impl Link {
+    // Put the message on the link behind anything already queued.
+    // Returns when it will have finished sending, or None if the queue is full
+    fn serialize(&mut self, now: Duration, conditions: &LinkConditions, message: &Message) -> Option<Duration> {
+        let bandwidth = conditions.bandwidth_bytes_per_sec;
+        if bandwidth == 0 {
+            return Some(now);
+        }
+
+        let size = wire::encode_message(message).len() as u64 + PACKET_OVERHEAD_BYTES;
+
+        // Whatever hasn't been sent yet is still taking up the queue
+        let queued = self.link_free_at.saturating_sub(now).as_secs_f64() * bandwidth as f64;
+        let queue_limit = conditions.send_queue_bytes;
+        if queue_limit > 0 && queued + size as f64 > queue_limit as f64 {
+            return None;
+        }
+
+        let send_time = Duration::from_secs_f64(size as f64 / bandwidth as f64);
+        self.link_free_at = self.link_free_at.max(now) + send_time;
+        Some(self.link_free_at)
+    }
 }
 
 pub struct UnreliableNetwork {
+    // Messages in the order they arrive
     messages: VecDeque<(Duration, i32, Message)>,
-    timer: Instant,
-    pub min_latency_ms: u64,
-    pub max_latency_ms: u64,
-    pub drop_rate: f32,
+    rng: SimRng,
+    clock: SharedClock,
+    // Conditions for any sender without their own
+    pub conditions: LinkConditions,
+    // Each sender has its own link so they don't share latency, loss or bandwidth
+    links: HashMap<i32, Link>,
+    // Messages dropped because the send queue was full
+    pub tail_drops: u64,
 }
 
 impl UnreliableNetwork {
     pub fn new() -> Self {
+        Self::with_rng_and_clock(SimRng::from_time(), Rc::new(SystemClock::new()))
+    }
+
+    // Same rng seed and clock gives the same drops and delays every run
+    pub fn with_rng_and_clock(rng: SimRng, clock: SharedClock) -> Self {
         UnreliableNetwork {
             messages: VecDeque::new(),
-            timer: Instant::now(),
-            min_latency_ms: 0,
-            max_latency_ms: 0,
-            drop_rate: 0.0,
+            rng,
+            clock,
+            conditions: LinkConditions::default(),
+            links: HashMap::new(),
+            tail_drops: 0,
         }
     }
 
+    // Queue a message to arrive after a latency picked from the link conditions
+    fn deliver_later(&mut self, conditions: &LinkConditions, sender_id: i32, message: Message, sent_at: Duration) {
+        let mut latency = conditions.sample_latency_ms(&mut self.rng);
+
+        // Occasionally hold a message back so the ones after it overtake it
+        if self.rng.chance(conditions.reorder_rate) {
+            latency += conditions.reorder_delay_ms;
+        }
+
+        let arrival = sent_at + Duration::from_millis(latency);
+
+        // Keep the queue sorted by arrival, after anything arriving at the same time
+        let index = self.messages.partition_point(|(other, _, _)| *other <= arrival);
+        self.messages.insert(index, (arrival, sender_id, message));
+    }
+}
+
+impl Default for UnreliableNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for UnreliableNetwork {
     // Send a message along with who sent it
-    pub fn send(&mut self, sender_id: i32, message: Message) {
-        // If the message is dropped, we don't send it
-        if rand::gen_range(0.0, 1.0) < self.drop_rate {
+    fn send(&mut self, sender_id: i32, message: Message) {
+        let now = self.clock.now();
+        let link = self.links.entry(sender_id).or_default();
+        let conditions = link.conditions.unwrap_or(self.conditions);
+
+        // Wait for the link to have room to send it
+        let Some(sent_at) = link.serialize(now, &conditions, &message) else {
+            self.tail_drops += 1;
             return;
-        }
+        };
 
-        // Simulate latency between two random values
-        let latency = rand::gen_range(self.min_latency_ms, self.max_latency_ms);
-        let delay = self.timer.elapsed() + Duration::from_millis(latency);
+        // Lost on the way, it still took up the link while it was being sent
+        if conditions.loss.should_drop(&mut self.rng, &mut link.in_bad_state) {
+            return;
+        }
 
-        self.messages.push_back((delay, sender_id, message));
+        if self.rng.chance(conditions.duplicate_rate) {
+            self.deliver_later(&conditions, sender_id, message.clone(), sent_at);
+        }
 
-        // Sort the messages by delay as messages can arrive out of order
-      //  self.messages.make_contiguous().sort_by(|a, b| a.0.cmp(&b.0));
+        self.deliver_later(&conditions, sender_id, message, sent_at);
     }
 
     // Returns the next message along with sender_id who sent the message
-    pub fn receive(&mut self) -> Option<(i32, Message)> {
-        if let Some((delay, sender_id, message)) = self.messages.pop_front() {
-            // If the delay has passed, we return the message
-            if delay <= self.timer.elapsed() {
-                return Some((sender_id, message));
+    fn receive(&mut self) -> Option<(i32, Message)> {
+        // The queue is in arrival order so only the front can be due
+        let (arrival, _, _) = self.messages.front()?;
+        if *arrival <= self.clock.now() {
+            return self
+                .messages
+                .pop_front()
+                .map(|(_, sender_id, message)| (sender_id, message));
+        }
+        None
+    }
+
+    fn conditions(&self) -> LinkConditions {
+        self.conditions
+    }
+
+    fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.conditions = conditions;
+    }
+
+    fn sender_conditions(&self, sender_id: i32) -> LinkConditions {
+        self.links
+            .get(&sender_id)
+            .and_then(|link| link.conditions)
+            .unwrap_or(self.conditions)
+    }
+
+    fn set_sender_conditions(&mut self, sender_id: i32, conditions: LinkConditions) {
+        self.links.entry(sender_id).or_default().conditions = Some(conditions);
+    }
+
+    // The longest wait on any of the links
+    fn queue_delay(&self) -> Duration {
+        let now = self.clock.now();
+        self.links
+            .values()
+            .map(|link| link.link_free_at.saturating_sub(now))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+// A simulated network that drops and delays the same way every run for the
+// same seed, with time from the given clock
+#[cfg(test)]
+pub(crate) fn seeded_network(seed: u64, clock: &SharedClock) -> SharedTransport {
+    Rc::new(RefCell::new(UnreliableNetwork::with_rng_and_clock(SimRng::new(seed), Rc::clone(clock))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::ManualClock, client::Client, server::Server};
+
+    // Position and health of every entity in a world, by entity id
+    type Entities = Vec<(i32, (f32, f32), i32)>;
+
+    // Where everything ended up
+    #[derive(Debug, PartialEq)]
+    struct Outcome {
+        server: Entities,
+        clients: Vec<Entities>,
+    }
+
+    fn positions(world: &crate::sim::World) -> Entities {
+        let mut positions: Vec<_> = world
+            .get_entities()
+            .iter()
+            .map(|(entity_id, entity)| (*entity_id, entity.position, entity.health))
+            .collect();
+        positions.sort_by_key(|(entity_id, _, _)| *entity_id);
+        positions
+    }
+
+    // Two clients moving and shooting over bad links, all driven by one seed
+    fn run(seed: u64) -> Outcome {
+        let clock = ManualClock::new();
+        let shared_clock: SharedClock = Rc::new(clock.clone());
+        let mut server = Server::with_rng_and_clock(
+            16,
+            seeded_network(seed, &shared_clock),
+            SimRng::new(seed),
+            Rc::clone(&shared_clock),
+        );
+        server.create_npc_entities();
+
+        let conditions = LinkConditions {
+            min_latency_ms: 30,
+            max_latency_ms: 120,
+            jitter: Jitter::Normal { std_dev_ms: 20.0 },
+            loss: LossModel::burst(0.1, 3.0),
+            duplicate_rate: 0.05,
+            reorder_rate: 0.05,
+            reorder_delay_ms: 40,
+            ..Default::default()
+        };
+
+        let mut clients: Vec<Client> = (1..=2)
+            .map(|id| {
+                let seed = seed + id as u64;
+                let mut client = Client::with_rng_and_clock(
+                    id,
+                    16,
+                    seeded_network(seed, &shared_clock),
+                    SimRng::new(seed),
+                    Rc::clone(&shared_clock),
+                );
+                client.keyboard_input_enabled = false;
+                client.colour = if id == 1 { Colour::Red } else { Colour::Green };
+                client.connect(&mut server, conditions, conditions);
+                client
+            })
+            .collect();
+
+        for step in 0..1500 {
+            for (index, client) in clients.iter_mut().enumerate() {
+                // Walk a square, shooting now and then
+                let leg = (step / 60 + index) % 4;
+                client.set_input(Input {
+                    left: leg == 0,
+                    down: leg == 1,
+                    right: leg == 2,
+                    up: leg == 3,
+                    fire: step % 25 == 0,
+                });
+                client.update();
             }
+            server.update();
+            clock.advance(Duration::from_millis(5));
+        }
 
-            // Otherwise we put it back
-            self.messages.push_front((delay, sender_id, message));
+        assert!(clients.iter().all(Client::is_connected), "a client never connected");
+
+        Outcome {
+            server: positions(&server.world),
+            clients: clients.iter().map(|client| positions(&client.world)).collect(),
         }
-        None
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn same_seed_replays_the_same_run() {
+        let first = run(7);
+        assert_eq!(first.server.len(), 3);
+        assert!(first.clients.iter().all(|world| world.len() == 3));
+        // The players walked somewhere rather than sitting at spawn
+        assert!(first.server.iter().any(|(_, position, _)| *position != (0.0, 0.0)));
+
+        assert_eq!(run(7), first);
+        assert_ne!(run(8), first);
+    }
+}
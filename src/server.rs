@@ -1,11 +1,81 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::{BTreeMap, HashMap, VecDeque}, rc::Rc, time::Duration};
 
-use crate::{client::Client, net::{Message, State, UnreliableNetwork}, sim::{Entity, Input, World}, ticktimer::TickTimer};
+use crate::{clock::{SharedClock, SystemClock}, combat::{self, CombatEvent, Weapon}, connection::Connection, handshake::{self, DenyReason, DisconnectReason, Handshake}, lagcompensation::{Positions, WorldHistory}, net::{Channel, Delta, Message, Pong, SharedTransport, State, UnreliableNetwork}, rng::SimRng, sim::{Colour, Entity, Input, Projectile, World, MAX_HEALTH}, ticktimer::TickTimer};
+
+// How many past snapshots we keep around to delta against
+const SNAPSHOT_HISTORY_LENGTH: usize = 64;
+
+/// What the server does on a tick where a client's input for it hasn't arrived
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingInput {
+    /// Leave the player where they are
+    #[default]
+    Idle,
+    /// Apply their last input again, guessing they're still holding the same keys
+    RepeatLast,
+}
+
+/// A connect request the server has answered with a challenge, waiting for
+/// the salt to come back. Along with the session they asked to carry on with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Challenge {
+    pub salt: u32,
+    pub colour: Colour,
+    pub session: Option<u32>,
+    pub nonce: u32,
+    // When we sent it
+    pub since: Duration,
+}
+
+/// Where a client is in connecting, from the server's side.
+/// Both keep the nonce of the connect request that got them there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    Challenged(Challenge),
+    /// A new request from a connected client is challenged like any other,
+    /// their connection carries on untouched until it's answered
+    Connected { nonce: u32, challenge: Option<Challenge> },
+}
+
+// A client's claim on their player, kept for a while after their connection
+// drops so they can connect again and carry on with it
+struct Session {
+    token: u32,
+    entity_id: i32,
+    // When the connection was lost, None while connected
+    dropped_at: Option<Duration>,
+}
+
+/// How early a clients inputs are reaching the server
+#[derive(Default, Debug, Clone, Copy)]
+pub struct InputTiming {
+    // Ticks ahead of the server the latest input arrived, waiting to be reported back
+    unreported_lead: Option<i32>,
+    // Inputs received and how many of those were for a tick we'd already simulated
+    pub received: u64,
+    pub late: u64,
+    // Ticks where no input was waiting. With clients sending every tick,
+    // even when idle, that means their input was lost or late
+    pub missing: u64,
+}
+
+impl InputTiming {
+    // Fraction of inputs that arrived too late
+    pub fn late_rate(&self) -> f32 {
+        if self.received == 0 {
+            return 0.0;
+        }
+        self.late as f32 / self.received as f32
+    }
+}
 
 /// Represents networked server
 pub struct Server {
     id: i32,
 
+    // Time for the tick timer and connections
+    clock: SharedClock,
+
     // Timer for fixed tickrate
     tick_timer: TickTimer,
 
@@ -13,55 +83,183 @@ pub struct Server {
     pub tick_rate_ms: u64,
 
     // The local servers network interface
-    network: Rc<RefCell<UnreliableNetwork>>,
+    network: SharedTransport,
+
+    // Map of an id to the connection to that client
+    connected_clients: HashMap<i32, Connection>,
 
-    // Map of an id to a client network interface
-    connected_clients: HashMap<i32, Rc<RefCell<UnreliableNetwork>>>,
+    // Where to send to each client id, like the address a packet came from
+    routes: HashMap<i32, SharedTransport>,
+    // Every client we've heard from that hasn't left
+    client_states: HashMap<i32, ClientState>,
+    // Clients past this many are denied
+    pub max_clients: usize,
+    // Clients we hear nothing from for this long are dropped, along with
+    // handshakes that aren't finished in this time
+    pub client_timeout: Duration,
+    on_disconnect: Option<Box<dyn FnMut(i32, DisconnectReason)>>,
+    // Every client with a player, connected or not, by client id
+    sessions: HashMap<i32, Session>,
+    // How long a timed out client's player is kept for them to reconnect to
+    pub session_grace: Duration,
+    // For picking challenge salts
+    rng: SimRng,
 
     // Server simulation data
     pub world: World,
 
     npc_entities: Vec<i32>,
 
-    // Map of the network id to the local sim entity id
-    networked_players: HashMap<i32, i32>,
+    // Map of the network id to the local sim entity id. Ordered so players
+    // are simulated, and shoot, in the same order every run
+    networked_players: BTreeMap<i32, i32>,
 
     // List of entities with their last tick rate that was integrated
     last_processed_input: HashMap<i32, i32>,
+
+    // Encoded size of the last round of snapshots, summed over every client,
+    // for keeping an eye on bandwidth
+    pub snapshot_bytes: usize,
+
+    // Recent world snapshots by the tick they were taken at, oldest first
+    snapshot_history: VecDeque<(i32, HashMap<i32, State>)>,
+
+    // Latest snapshot tick each client has told us it received
+    acked_snapshots: HashMap<i32, i32>,
+
+    // Only send what changed since the snapshot a client acknowledged
+    pub delta_compression_enabled: bool,
+
+    // Newest ping from each client, answered in their next snapshot
+    pending_pongs: HashMap<i32, u32>,
+
+    // How early each clients input is arriving, reported back so they can adjust their tick rate
+    input_timing: HashMap<i32, InputTiming>,
+
+    // Inputs waiting to be simulated by sequence, along with the tick the client
+    // was viewing when they made it. Clients number input by the tick they want
+    // it simulated on, so each tick uses the input numbered for it. Early inputs
+    // wait for their tick, which rides out jitter and keeps bursts and gaps in
+    // arrival from changing how fast players move
+    input_buffers: HashMap<i32, BTreeMap<i32, (Input, Option<f32>)>>,
+    // Furthest ahead of the current tick an input is held, anything earlier than
+    // that is thrown away. Clients aim to be target_input_lead ticks ahead
+    pub input_buffer_depth: usize,
+    pub missing_input: MissingInput,
+    // Last input simulated for each client, for repeating
+    last_input: HashMap<i32, Input>,
+
+    // Recent entity positions for rewinding to what a client saw
+    world_history: WorldHistory,
+    // Furthest back we'll rewind, so a client with a huge lag can't act on a world long gone
+    pub max_rewind_ms: u64,
+    // The tick each client was viewing when they made the input being simulated
+    client_view_ticks: HashMap<i32, f32>,
+
+    // What players shoot with when they press fire
+    pub weapon: Weapon,
+    // Tick each entity last fired on, for the cooldown
+    last_fired: HashMap<i32, i32>,
+    // How many ticks behind the server its shooter was viewing, for each projectile in
+    // flight. It's checked against the world that far back for as long as it flies
+    projectile_view_lag: HashMap<i32, f32>,
+    // Waiting to be sent to every client at the end of the tick
+    pending_events: Vec<CombatEvent>,
+    pending_despawns: Vec<i32>,
 }
 
 impl Server {
     pub fn new(tick_rate_ms: u64) -> Self {
+        Self::with_network(tick_rate_ms, Rc::new(RefCell::new(UnreliableNetwork::new())))
+    }
+
+    // Create a server that receives on the given transport
+    pub fn with_network(tick_rate_ms: u64, network: SharedTransport) -> Self {
+        Self::with_rng_and_clock(tick_rate_ms, network, SimRng::from_time(), Rc::new(SystemClock::new()))
+    }
+
+    // Create a server whose ticks and connections run off the given clock.
+    // Same rng seed and clock picks the same salts and session tokens every run
+    pub fn with_rng_and_clock(tick_rate_ms: u64, network: SharedTransport, rng: SimRng, clock: SharedClock) -> Self {
         Server {
             id: 0,
-            tick_timer: TickTimer::new(std::time::Duration::from_millis(tick_rate_ms)),
+            tick_timer: TickTimer::with_clock(std::time::Duration::from_millis(tick_rate_ms), Rc::clone(&clock)),
+            clock,
             tick_rate_ms,
-            network: Rc::new(RefCell::new(UnreliableNetwork::new())),
+            network,
             connected_clients: HashMap::new(),
+            routes: HashMap::new(),
+            client_states: HashMap::new(),
+            max_clients: 8,
+            client_timeout: Duration::from_secs(3),
+            on_disconnect: None,
+            sessions: HashMap::new(),
+            session_grace: Duration::from_secs(10),
+            rng,
             world: World::new(),
             npc_entities: Vec::new(),
-            networked_players: HashMap::new(),
+            networked_players: BTreeMap::new(),
             last_processed_input: HashMap::new(),
+            snapshot_bytes: 0,
+            snapshot_history: VecDeque::new(),
+            acked_snapshots: HashMap::new(),
+            delta_compression_enabled: true,
+            pending_pongs: HashMap::new(),
+            input_timing: HashMap::new(),
+            input_buffers: HashMap::new(),
+            input_buffer_depth: 4,
+            missing_input: MissingInput::default(),
+            last_input: HashMap::new(),
+            world_history: WorldHistory::new(),
+            max_rewind_ms: 500,
+            client_view_ticks: HashMap::new(),
+            weapon: Weapon::default(),
+            last_fired: HashMap::new(),
+            projectile_view_lag: HashMap::new(),
+            pending_events: Vec::new(),
+            pending_despawns: Vec::new(),
         }
     }
 
-    pub fn get_network(&self) -> Rc<RefCell<UnreliableNetwork>> {
+    pub fn get_network(&self) -> SharedTransport {
         Rc::clone(&self.network)
     }
 
     pub fn create_npc_entities(&mut self) {
         // Create non player entities
+        // Spread them out so each new one can be seen
         let mut entity = Entity::new();
-        entity.position = (100., 100.);
+        entity.position = (100. + 60. * self.npc_entities.len() as f32, 100.);
         entity.colour = crate::sim::Colour::Blue;
         let npc_id = self.world.add_entity(entity);
 
         self.npc_entities.push(npc_id);
     }
 
+    // Remove the newest npc, if there are any
+    pub fn remove_npc_entity(&mut self) {
+        if let Some(npc_id) = self.npc_entities.last().copied() {
+            self.despawn_entity(npc_id);
+        }
+    }
+
+    // Remove an entity from the world and tell every client to do the same
+    pub fn despawn_entity(&mut self, entity_id: i32) -> Option<Entity> {
+        let entity = self.world.remove_entity(entity_id)?;
+
+        self.npc_entities.retain(|npc_id| *npc_id != entity_id);
+        self.last_fired.remove(&entity_id);
+        self.pending_despawns.push(entity_id);
+
+        Some(entity)
+    }
+
     pub fn update_npc_entities(&mut self, tick: i32) {
         for npc_id in self.npc_entities.iter() {
-            let entity = self.world.get_entity(*npc_id).unwrap();
+            // Could have been removed from the world directly
+            let Some(entity) = self.world.get_entity(*npc_id) else {
+                continue;
+            };
 
             // Move the npc entities in a circle
             let ticks_ms = tick as f32 * self.tick_rate_ms as f32;
@@ -73,23 +271,201 @@ impl Server {
         }
     }
 
-    // This is a function to fake connections on our fake network
-    // up the network connection.
-    // In the real world this would happen via network messages.
-    // The server version of stores the client that wants to connect
-    // and creates the entity for mirroring.
-    pub fn connect(&mut self, client: &mut Client) -> i32 {
-        let client_network = client.get_network();
-        self.connected_clients.insert(client.get_id(), client_network);
+    // Tell the server how to reach a client id. Over UDP it's learnt from the
+    // address that answered their challenge, our fake network has no
+    // addresses so it has to be told
+    pub fn add_route(&mut self, client_id: i32, client_network: SharedTransport) {
+        self.routes.insert(client_id, client_network);
+    }
+
+    pub fn client_state(&self, client_id: i32) -> Option<ClientState> {
+        self.client_states.get(&client_id).copied()
+    }
+
+    // Send a handshake message straight to a client, outside of any connection
+    fn send_handshake(&self, client_id: i32, handshake: Handshake) {
+        self.reply_handshake(self.routes.get(&client_id), handshake);
+    }
+
+    // Send a handshake message back the way one came in, which isn't
+    // necessarily the route we send everything else to them on
+    fn reply_handshake(&self, reply: Option<&SharedTransport>, handshake: Handshake) {
+        if let Some(route) = reply {
+            route.borrow_mut().send(
+                self.id,
+                Message {
+                    handshake: Some(handshake),
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    // A client asking to connect gets a challenge, sending it back gets them
+    // accepted. Both are asked again until answered so repeats are expected.
+    // Anyone can claim to be a client, so replies go back to wherever the
+    // message came from and only answering the challenge earns them the route.
+    // Returns true when it did
+    fn handle_handshake(&mut self, client_id: i32, handshake: Handshake, reply: Option<SharedTransport>) -> bool {
+        let reply = reply.or_else(|| self.routes.get(&client_id).cloned());
+        match handshake {
+            Handshake::ConnectRequest { colour, session, nonce } => {
+                // Repeats of the request get the same challenge, a new attempt gets a new one
+                let salt = match self.client_state(client_id) {
+                    // A late or duplicated copy of the request they connected with
+                    Some(ClientState::Connected { nonce: connected_nonce, .. }) if nonce == connected_nonce => return false,
+                    Some(ClientState::Challenged(challenge))
+                    | Some(ClientState::Connected {
+                        challenge: Some(challenge),
+                        ..
+                    }) if nonce == challenge.nonce => challenge.salt,
+                    state => {
+                        let connected_nonce = match state {
+                            Some(ClientState::Connected { nonce, .. }) => Some(nonce),
+                            _ => None,
+                        };
+                        // A connected client already has their place
+                        if connected_nonce.is_none() && self.connected_clients.len() >= self.max_clients {
+                            self.reply_handshake(reply.as_ref(), Handshake::Deny { reason: DenyReason::ServerFull });
+                            return false;
+                        }
+
+                        let challenge = Challenge {
+                            salt: (self.rng.next_u64() >> 32) as u32,
+                            colour,
+                            session,
+                            nonce,
+                            since: self.clock.now(),
+                        };
+                        let state = match connected_nonce {
+                            Some(nonce) => ClientState::Connected {
+                                nonce,
+                                challenge: Some(challenge),
+                            },
+                            None => ClientState::Challenged(challenge),
+                        };
+                        self.client_states.insert(client_id, state);
+                        challenge.salt
+                    }
+                };
+                self.reply_handshake(reply.as_ref(), Handshake::Challenge { salt, nonce });
+            }
+            Handshake::ChallengeResponse { salt } => {
+                let (challenge, connected) = match self.client_state(client_id) {
+                    Some(ClientState::Challenged(challenge)) => (Some(challenge), false),
+                    Some(ClientState::Connected { challenge, .. }) => (challenge, true),
+                    // We gave up waiting on them, tell them so they ask again rather
+                    // than keep answering a challenge we've forgotten
+                    None => {
+                        self.reply_handshake(
+                            reply.as_ref(),
+                            Handshake::Deny {
+                                reason: DenyReason::ChallengeExpired,
+                            },
+                        );
+                        return false;
+                    }
+                };
+
+                match challenge {
+                    Some(challenge) if salt == challenge.salt => {
+                        let Some(route) = reply else {
+                            return false;
+                        };
+                        self.routes.insert(client_id, Rc::clone(&route));
+
+                        // They've started over. Their old connection is no use, but
+                        // they can keep their player if they have the session for it
+                        if connected {
+                            if challenge.session.is_some() && challenge.session == self.session_token(client_id) {
+                                self.drop_connection(client_id);
+                            } else {
+                                self.disconnected(client_id, DisconnectReason::Left);
+                            }
+                        }
+
+                        let resuming = challenge.session.is_some_and(|token| {
+                            self.sessions
+                                .get(&client_id)
+                                .is_some_and(|session| session.token == token && session.dropped_at.is_some())
+                        });
+                        if resuming {
+                            self.resume_client(client_id, route, challenge.nonce);
+                        } else {
+                            self.add_client(client_id, route, challenge.colour, challenge.nonce);
+                        }
+                        self.send_accept(client_id);
+                        return true;
+                    }
+                    // Our accept was lost
+                    _ if connected => self.send_accept(client_id),
+                    // Left over from an earlier attempt than the one we're challenging
+                    _ => {}
+                }
+            }
+            Handshake::Disconnect => {
+                self.client_states.remove(&client_id);
+                self.disconnected(client_id, DisconnectReason::Left);
+            }
+            // Only clients are sent anything else
+            _ => {}
+        }
+        false
+    }
+
+    // Tell a client to leave, then forget about them
+    pub fn disconnect_client(&mut self, client_id: i32) {
+        for _ in 0..handshake::DISCONNECT_REDUNDANCY {
+            self.send_handshake(client_id, Handshake::Disconnect);
+        }
+        self.client_states.remove(&client_id);
+        self.disconnected(client_id, DisconnectReason::Kicked);
+    }
+
+    fn send_accept(&self, client_id: i32) {
+        if let Some(session) = self.sessions.get(&client_id) {
+            self.send_handshake(
+                client_id,
+                Handshake::Accept {
+                    entity_id: session.entity_id,
+                    session: session.token,
+                },
+            );
+        }
+    }
+
+    fn session_token(&self, client_id: i32) -> Option<u32> {
+        self.sessions.get(&client_id).map(|session| session.token)
+    }
+
+    // Sets up a client that passed the handshake, returning the entity they control
+    fn add_client(&mut self, client_id: i32, client_network: SharedTransport, colour: Colour, nonce: u32) -> i32 {
+        // Start from scratch if they've connected before
+        self.end_session(client_id);
+        self.forget_connection(client_id);
+
+        self.connected_clients.insert(client_id, Connection::with_clock(self.id, client_network, Rc::clone(&self.clock)));
+        self.client_states
+            .insert(client_id, ClientState::Connected { nonce, challenge: None });
 
         // Create a new entity for the client
         let mut entity = Entity::new();
         entity.position = (0., 0.);
-        entity.colour = client.colour;
+        entity.colour = colour;
         let entity_id = self.world.add_entity(entity);
 
         // Store the network id to the entity id
-        self.networked_players.insert(client.get_id(), entity_id);
+        self.networked_players.insert(client_id, entity_id);
+
+        let token = (self.rng.next_u64() >> 32) as u32;
+        self.sessions.insert(
+            client_id,
+            Session {
+                token,
+                entity_id,
+                dropped_at: None,
+            },
+        );
 
         // Return it for assignment
         // In real world this assignment would probably happen via a RPC
@@ -98,38 +474,482 @@ impl Server {
 
     pub fn update(&mut self) {
 
+        self.network.borrow_mut().poll();
+
         // Fixed tickrate
         for tick in self.tick_timer.tick() {
             //println!("Server tick: {}", tick);
             self.update_npc_entities(tick);
 
-            self.process_client_messages();
-            self.broadcast_state(tick)
+            self.process_client_messages(tick);
+            self.update_projectiles(tick);
+            self.apply_client_inputs(tick);
+
+            // Remember the world as it's about to be sent out
+            let max_ticks = self.max_rewind_ticks() + 1;
+            self.world_history.record(tick, &self.world, max_ticks);
+
+            self.broadcast_state(tick);
+            self.broadcast_events();
+
+            for connection in self.connected_clients.values_mut() {
+                connection.update();
+            }
         }
+
+        self.check_timeouts();
+    }
+
+    // Called with the client id and why whenever a connected client is dropped
+    pub fn on_disconnect(&mut self, callback: impl FnMut(i32, DisconnectReason) + 'static) {
+        self.on_disconnect = Some(Box::new(callback));
+    }
+
+    // Give a client that dropped their player back, along with the inputs we've
+    // processed for them so nothing they already sent is simulated twice
+    fn resume_client(&mut self, client_id: i32, client_network: SharedTransport, nonce: u32) {
+        let Some(session) = self.sessions.get_mut(&client_id) else {
+            return;
+        };
+        session.dropped_at = None;
+        let entity_id = session.entity_id;
+
+        self.connected_clients.insert(client_id, Connection::with_clock(self.id, client_network, Rc::clone(&self.clock)));
+        self.client_states
+            .insert(client_id, ClientState::Connected { nonce, challenge: None });
+        self.networked_players.insert(client_id, entity_id);
+    }
+
+    // Drop a connected client, letting on_disconnect know. Those that timed out
+    // keep their player for session_grace in case they come back
+    fn disconnected(&mut self, client_id: i32, reason: DisconnectReason) {
+        let was_connected = match reason {
+            DisconnectReason::TimedOut => self.drop_connection(client_id),
+            DisconnectReason::Left | DisconnectReason::Kicked => self.remove_client(client_id),
+        };
+        if !was_connected {
+            return;
+        }
+        if let Some(on_disconnect) = &mut self.on_disconnect {
+            on_disconnect(client_id, reason);
+        }
+    }
+
+    // Drop clients that have gone quiet and handshakes that were never finished
+    fn check_timeouts(&mut self) {
+        let now = self.clock.now();
+
+        let timed_out: Vec<i32> = self
+            .connected_clients
+            .iter()
+            .filter(|(_, connection)| connection.time_since_received() >= self.client_timeout)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in timed_out {
+            self.disconnected(client_id, DisconnectReason::TimedOut);
+        }
+
+        let stale = |challenge: &Challenge| now.saturating_sub(challenge.since) >= self.client_timeout;
+        self.client_states.retain(|_, state| match state {
+            ClientState::Challenged(challenge) => !stale(challenge),
+            ClientState::Connected { challenge, .. } => {
+                // Only the new attempt is given up on, not their connection
+                if challenge.as_ref().is_some_and(stale) {
+                    *challenge = None;
+                }
+                true
+            }
+        });
+
+        let expired: Vec<i32> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| {
+                session
+                    .dropped_at
+                    .is_some_and(|dropped_at| now.saturating_sub(dropped_at) >= self.session_grace)
+            })
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in expired {
+            self.end_session(client_id);
+        }
+    }
+
+    pub fn input_timing(&self, client_id: i32) -> Option<&InputTiming> {
+        self.input_timing.get(&client_id)
+    }
+
+    // Drop a client and despawn their player, e.g. when they disconnect.
+    // Returns false if they weren't connected
+    pub fn remove_client(&mut self, client_id: i32) -> bool {
+        let was_connected = self.drop_connection(client_id);
+        self.end_session(client_id);
+        was_connected
+    }
+
+    // Close a client's connection but leave their player where it is.
+    // Returns false if they weren't connected
+    fn drop_connection(&mut self, client_id: i32) -> bool {
+        // Anything they're still being challenged on can carry on without the connection
+        if let Some(ClientState::Connected {
+            challenge: Some(challenge),
+            ..
+        }) = self.client_states.remove(&client_id)
+        {
+            self.client_states.insert(client_id, ClientState::Challenged(challenge));
+        }
+        if self.connected_clients.remove(&client_id).is_none() {
+            return false;
+        }
+
+        self.forget_connection(client_id);
+        self.networked_players.remove(&client_id);
+        if let Some(session) = self.sessions.get_mut(&client_id) {
+            session.dropped_at = Some(self.clock.now());
+        }
+
+        true
+    }
+
+    // Despawn a client's player now nobody can come back to it
+    fn end_session(&mut self, client_id: i32) {
+        self.last_processed_input.remove(&client_id);
+        if let Some(session) = self.sessions.remove(&client_id) {
+            self.despawn_entity(session.entity_id);
+        }
+    }
+
+    // Throw away what we were keeping track of for a client's connection,
+    // which a new connection has to build up again
+    fn forget_connection(&mut self, client_id: i32) {
+        self.acked_snapshots.remove(&client_id);
+        self.pending_pongs.remove(&client_id);
+        self.input_timing.remove(&client_id);
+        self.input_buffers.remove(&client_id);
+        self.last_input.remove(&client_id);
+        self.client_view_ticks.remove(&client_id);
+    }
+
+    // Every entities position as the client saw it when they made the input
+    // being simulated for them this tick, for checking things like what they
+    // were aiming at. Limited to max_rewind_ms ago
+    pub fn client_view(&self, client_id: i32) -> Option<Positions> {
+        let view_tick = self.client_view_ticks.get(&client_id)?;
+        self.world_history.rewind(*view_tick, &self.world)
+    }
+
+    pub fn client_view_tick(&self, client_id: i32) -> Option<f32> {
+        self.client_view_ticks.get(&client_id).copied()
+    }
+
+    // Every entities position as it is right now
+    fn current_positions(&self) -> Positions {
+        self.world
+            .get_entities()
+            .iter()
+            .map(|(entity_id, entity)| (*entity_id, entity.position))
+            .collect()
+    }
+
+    fn max_rewind_ticks(&self) -> usize {
+        (self.max_rewind_ms / self.tick_rate_ms.max(1)) as usize
+    }
+
+    // How many inputs from a client are waiting to be simulated
+    pub fn input_buffer_len(&self, client_id: i32) -> usize {
+        self.input_buffers.get(&client_id).map_or(0, |buffer| buffer.len())
     }
 
-    fn process_client_messages(&mut self) {
-        let mut network = self.network.borrow_mut();
+    fn process_client_messages(&mut self, tick: i32) {
+        // Our own handle so the server can be borrowed while receiving
+        let network = Rc::clone(&self.network);
+        let mut network = network.borrow_mut();
         // Process all pending messages from clients
-        while let Some((client_id, message)) = network.receive() {
-            // Get the entity based on the one we're wanting to update
-            // Look up the entity id based on the network id
-            let local_entity_id = self.networked_players.get(&client_id).unwrap();
-
-            let entity = self.world.get_entity(*local_entity_id).unwrap();
-
-            // Integrate the client input from the message into the sim
-            if let Some(input) = message.input {
-                entity.integrate_input(&Input {
-                    left: input.0,
-                    right: input.1,
-                    up: input.2,
-                    down: input.3,
+        while let Some((client_id, packet)) = network.receive() {
+            if let Some(handshake) = packet.handshake {
+                // Answer them wherever they're asking from, which can change if they restart
+                let reply = network.reply_to(client_id);
+                if self.handle_handshake(client_id, handshake, reply) {
+                    network.bind_sender(client_id);
+                }
+                continue;
+            }
+
+            let Some(connection) = self.connected_clients.get_mut(&client_id) else {
+                // Still sending on a connection we've dropped, e.g. after timing
+                // them out, so let them know rather than leave them waiting
+                if packet.packet.is_some() && self.client_state(client_id).is_none() {
+                    let reply = network.reply_to(client_id).or_else(|| self.routes.get(&client_id).cloned());
+                    self.reply_handshake(reply.as_ref(), Handshake::Disconnect);
+                }
+                continue;
+            };
+
+            // Let the connection handle acks and throw away anything we've already had
+            for message in connection.receive(packet) {
+                // Resent inputs fill in for any packets of ours that were lost
+                // The client's view moves on a tick for every tick of input
+                for (sequence, input) in &message.previous_inputs {
+                    let view_tick = message
+                        .view_tick
+                        .map(|view_tick| view_tick - message.sequence.wrapping_sub(*sequence) as f32);
+                    self.buffer_input(tick, client_id, *sequence, *input, view_tick);
+                }
+
+                // Queue the client input from the message to be simulated on its tick
+                if let Some(input) = message.input {
+                    self.buffer_input(tick, client_id, message.sequence, input, message.view_tick);
+
+                    // Clients number input by the tick they want it simulated on
+                    let lead = message.sequence.wrapping_sub(tick);
+                    let timing = self.input_timing.entry(client_id).or_default();
+                    timing.unreported_lead = Some(lead);
+                    timing.received += 1;
+                    if lead < 0 {
+                        timing.late += 1;
+                    }
+                }
+
+                if let Some(ping) = message.ping {
+                    self.pending_pongs.insert(client_id, ping);
+                }
+
+                // Acks can arrive out of order so only ever move forward
+                if let Some(snapshot_ack) = message.snapshot_ack {
+                    let acked = self.acked_snapshots.entry(client_id).or_insert(snapshot_ack);
+                    *acked = (*acked).max(snapshot_ack);
+                }
+            }
+        }
+    }
+
+    fn buffer_input(
+        &mut self,
+        tick: i32,
+        client_id: i32,
+        sequence: i32,
+        input: Input,
+        view_tick: Option<f32>,
+    ) {
+        // Anything for a tick we've already simulated is too late to use,
+        // this also throws away copies of inputs we've already had
+        let too_late = sequence.wrapping_sub(tick) < 0;
+        // Too far ahead to be meant for us, e.g. numbered before the client synced its tick
+        let too_early = sequence.wrapping_sub(tick) > self.input_buffer_depth as i32;
+        if too_late || too_early {
+            return;
+        }
+
+        self.input_buffers
+            .entry(client_id)
+            .or_default()
+            .insert(sequence, (input, view_tick));
+    }
+
+    // Simulate every client's input for this tick, treating it as missing if it
+    // hasn't arrived. Inputs for later ticks stay in the buffer
+    fn apply_client_inputs(&mut self, tick: i32) {
+        let mut shooters = Vec::new();
+
+        for (client_id, entity_id) in &self.networked_players {
+            let next = self.input_buffers.get_mut(client_id).and_then(|buffer| {
+                // Nothing before this tick can be used any more
+                while buffer.first_key_value().is_some_and(|(sequence, _)| sequence.wrapping_sub(tick) < 0) {
+                    buffer.pop_first();
+                }
+                buffer.remove(&tick)
+            });
+
+            // This tick is done with whether or not their input made it, one
+            // that turns up later is too late. The client takes this as the
+            // last input we processed and predicts on from here
+            self.last_processed_input.insert(*client_id, tick);
+
+            let input = match next {
+                Some((input, view_tick)) => {
+                    self.last_input.insert(*client_id, input);
+                    if let Some(view_tick) = view_tick {
+                        self.client_view_ticks.insert(*client_id, view_tick);
+                    }
+                    input
+                }
+                None => {
+                    if let Some(timing) = self.input_timing.get_mut(client_id) {
+                        timing.missing += 1;
+                    }
+
+                    match self.missing_input {
+                        MissingInput::Idle => continue,
+                        MissingInput::RepeatLast => match self.last_input.get(client_id) {
+                            Some(input) => *input,
+                            None => continue,
+                        },
+                    }
+                }
+            };
+
+            if let Some(entity) = self.world.get_entity(*entity_id) {
+                entity.integrate_input(&input);
+            }
+
+            if input.fire {
+                shooters.push((*client_id, *entity_id));
+            }
+        }
+
+        // Everyone moves before anyone shoots
+        for (client_id, entity_id) in shooters {
+            self.fire(tick, client_id, entity_id);
+        }
+    }
+
+    fn fire(&mut self, tick: i32, client_id: i32, entity_id: i32) {
+        let ready = self
+            .last_fired
+            .get(&entity_id)
+            .is_none_or(|last| tick.wrapping_sub(*last) >= combat::FIRE_COOLDOWN_TICKS);
+        let Some(shooter) = self.world.get_entities().get(&entity_id).copied() else {
+            return;
+        };
+        if !ready {
+            return;
+        }
+        self.last_fired.insert(entity_id, tick);
+
+        let from = shooter.centre();
+        let direction = shooter.facing;
+
+        match self.weapon {
+            Weapon::Hitscan => {
+                // Aim at everyone where the shooter saw them, not where they are now
+                let positions = self
+                    .client_view(client_id)
+                    .unwrap_or_else(|| self.current_positions());
+                let hit = combat::raycast(from, direction, combat::HITSCAN_RANGE, &positions, entity_id);
+
+                let distance = hit.map_or(combat::HITSCAN_RANGE, |(_, distance)| distance);
+                let to = (from.0 + direction.0 * distance, from.1 + direction.1 * distance);
+                self.pending_events.push(CombatEvent::Shot {
+                    shooter: entity_id,
+                    from,
+                    to,
                 });
+
+                if let Some((target, _)) = hit {
+                    self.apply_damage(entity_id, target, combat::HITSCAN_DAMAGE, None);
+                }
             }
+            Weapon::Projectile => {
+                let projectile = Projectile {
+                    owner: entity_id,
+                    position: from,
+                    velocity: (
+                        direction.0 * combat::PROJECTILE_SPEED,
+                        direction.1 * combat::PROJECTILE_SPEED,
+                    ),
+                    ticks_left: combat::PROJECTILE_LIFETIME_TICKS,
+                };
+                let projectile_id = self.world.add_projectile(projectile);
+
+                let view_lag = self
+                    .client_view_tick(client_id)
+                    .map_or(0.0, |view_tick| (tick as f32 - view_tick).max(0.0));
+                self.projectile_view_lag.insert(projectile_id, view_lag);
+
+                self.pending_events.push(CombatEvent::ProjectileFired {
+                    projectile_id,
+                    projectile,
+                });
+            }
+        }
+    }
+
+    // Check what each projectile passes through this tick, then move them all along
+    fn update_projectiles(&mut self, tick: i32) {
+        let mut hits = Vec::new();
+
+        // In id order, so when two hit on the same tick it's the same one first every run
+        let mut projectiles: Vec<_> = self.world.get_projectiles().iter().collect();
+        projectiles.sort_by_key(|(projectile_id, _)| **projectile_id);
 
-            // Store the last sequence(or tick in our case) we processed input for
-            self.last_processed_input.insert(client_id, message.sequence);
+        for (projectile_id, projectile) in projectiles {
+            // Against the world as its shooter was seeing it
+            let view_lag = self.projectile_view_lag.get(projectile_id).copied().unwrap_or(0.0);
+            let positions = self
+                .world_history
+                .rewind(tick as f32 - view_lag, &self.world)
+                .unwrap_or_else(|| self.current_positions());
+
+            let (dx, dy) = projectile.velocity;
+            let speed = (dx * dx + dy * dy).sqrt();
+            if speed == 0.0 {
+                continue;
+            }
+
+            let direction = (dx / speed, dy / speed);
+            if let Some((target, _)) =
+                combat::raycast(projectile.position, direction, speed, &positions, projectile.owner)
+            {
+                hits.push((*projectile_id, projectile.owner, target));
+            }
+        }
+
+        for (projectile_id, shooter, target) in hits {
+            self.world.remove_projectile(projectile_id);
+            self.apply_damage(shooter, target, combat::PROJECTILE_DAMAGE, Some(projectile_id));
+        }
+
+        self.world.update_projectiles();
+
+        let projectiles = self.world.get_projectiles();
+        self.projectile_view_lag
+            .retain(|projectile_id, _| projectiles.contains_key(projectile_id));
+    }
+
+    fn apply_damage(&mut self, shooter: i32, target: i32, damage: i32, projectile: Option<i32>) {
+        let is_player = self.networked_players.values().any(|entity_id| *entity_id == target);
+        let Some(entity) = self.world.get_entity(target) else {
+            return;
+        };
+
+        entity.health = (entity.health - damage).max(0);
+        self.pending_events.push(CombatEvent::Hit {
+            shooter,
+            target,
+            projectile,
+            health: entity.health,
+        });
+
+        if entity.health == 0 {
+            // Back to full, players start over where they spawned
+            entity.health = MAX_HEALTH;
+            if is_player {
+                entity.position = (0., 0.);
+            }
+            self.pending_events.push(CombatEvent::Kill { shooter, target });
+        }
+    }
+
+    // Send everything that happened this tick to every client, reliably and in order
+    fn broadcast_events(&mut self) {
+        if self.pending_events.is_empty() && self.pending_despawns.is_empty() {
+            return;
+        }
+
+        let events = std::mem::take(&mut self.pending_events);
+        let despawns = std::mem::take(&mut self.pending_despawns);
+        for (client_id, connection) in self.connected_clients.iter_mut() {
+            let last_processed_tick = self.last_processed_input.get(client_id).unwrap_or(&0);
+            connection.send_on(
+                Channel::ReliableOrdered,
+                Message {
+                    sequence: *last_processed_tick,
+                    events: events.clone(),
+                    despawns: despawns.clone(),
+                    ..Default::default()
+                },
+            );
         }
     }
 
@@ -148,20 +968,287 @@ impl Server {
             world_state.push(state);
         }
 
+        // Keep this snapshot so later ones can be sent relative to it
+        self.snapshot_history.push_back((
+            tick,
+            world_state.iter().map(|state| (state.entity_id, *state)).collect(),
+        ));
+        if self.snapshot_history.len() > SNAPSHOT_HISTORY_LENGTH {
+            self.snapshot_history.pop_front();
+        }
+
         // Broadcast the state to all connected clients
         // This might happen at a different rate than the tickrate
-        for (client_id, client_network) in self.connected_clients.iter() {
+        let mut snapshot_bytes = 0;
+        for (client_id, connection) in self.connected_clients.iter_mut() {
 
             let last_processed_tick = self.last_processed_input.get(client_id).unwrap_or(&0);
 
+            // Delta against the last snapshot the client acknowledged, if we still have it.
+            // Otherwise they get everything
+            let baseline = self
+                .acked_snapshots
+                .get(client_id)
+                .filter(|_| self.delta_compression_enabled)
+                .and_then(|acked_tick| {
+                    self.snapshot_history
+                        .iter()
+                        .find(|(snapshot_tick, _)| snapshot_tick == acked_tick)
+                });
+
+            let (state, delta) = match baseline {
+                Some((baseline_tick, baseline)) => {
+                    (None, Some(Delta::between(*baseline_tick, baseline, &world_state)))
+                }
+                None => (Some(world_state.clone()), None),
+            };
+
+            // Answered in the same tick the ping was read so the client can
+            // take the round trip as the network time
+            let pong = self.pending_pongs.remove(client_id).map(|ping_time| Pong {
+                ping_time,
+                server_tick: tick,
+                tick_interval_ms: self.tick_rate_ms as u32,
+            });
+
+            let input_lead = self
+                .input_timing
+                .get_mut(client_id)
+                .and_then(|timing| timing.unreported_lead.take());
+
             let message = Message {
-                state: Some(world_state.clone()),
+                state,
+                delta,
+                snapshot_tick: Some(tick),
+                pong,
+                input_lead,
                 input: None, // Unused
                 sequence: *last_processed_tick, // Send the server tick so we know what state we're at
+                ..Default::default()
             };
 
-            let mut client_network = client_network.borrow_mut();
-            client_network.send(self.id, message);
+            connection.send(message);
+            snapshot_bytes += connection.last_packet_bytes();
         }
+        self.snapshot_bytes = snapshot_bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::Client,
+        clock::ManualClock,
+        handshake::ConnectionState,
+        net::{seeded_network, LinkConditions, LossModel},
+        wire,
+    };
+
+    struct Setup {
+        clock: ManualClock,
+        server: Server,
+        client: Client,
+    }
+
+    // A server and one client over simulated links that lose nothing
+    fn setup() -> Setup {
+        let clock = ManualClock::new();
+        let shared_clock: SharedClock = Rc::new(clock.clone());
+        let mut server =
+            Server::with_rng_and_clock(16, seeded_network(1, &shared_clock), SimRng::new(1), Rc::clone(&shared_clock));
+        let mut client =
+            Client::with_rng_and_clock(1, 16, seeded_network(2, &shared_clock), SimRng::new(2), Rc::clone(&shared_clock));
+        client.keyboard_input_enabled = false;
+
+        let conditions = LinkConditions {
+            min_latency_ms: 20,
+            max_latency_ms: 20,
+            ..Default::default()
+        };
+        client.connect(&mut server, conditions, conditions);
+
+        Setup { clock, server, client }
+    }
+
+    impl Setup {
+        fn step(&mut self) {
+            self.client.update();
+            self.server.update();
+            self.clock.advance(Duration::from_millis(5));
+        }
+
+        fn run_for(&mut self, duration: Duration) {
+            for _ in 0..duration.as_millis() / 5 {
+                self.step();
+            }
+        }
+
+        // Run until the server has challenged the client, then lose everything
+        // the client sends for a while, starting with its answer
+        fn cut_upstream_after_challenge(&mut self, outage: Duration) {
+            while !matches!(self.server.client_state(1), Some(ClientState::Challenged(_))) {
+                self.step();
+            }
+
+            let network = self.server.get_network();
+            let conditions = network.borrow().sender_conditions(1);
+            network.borrow_mut().set_sender_conditions(
+                1,
+                LinkConditions {
+                    loss: LossModel::Uniform { drop_rate: 1.0 },
+                    ..conditions
+                },
+            );
+            self.run_for(outage);
+            network.borrow_mut().set_sender_conditions(1, conditions);
+        }
+    }
+
+    #[test]
+    fn late_copy_of_a_connect_request_is_ignored() {
+        let mut setup = setup();
+        setup.run_for(Duration::from_millis(500));
+        assert_eq!(setup.client.state, ConnectionState::Connected);
+        let Some(ClientState::Connected { nonce, .. }) = setup.server.client_state(1) else {
+            panic!("client not connected on the server");
+        };
+
+        // The request the client connected with turns up again
+        setup.server.get_network().borrow_mut().send(
+            1,
+            Message {
+                handshake: Some(Handshake::ConnectRequest {
+                    colour: Colour::Red,
+                    session: None,
+                    nonce,
+                }),
+                ..Default::default()
+            },
+        );
+        setup.run_for(Duration::from_secs(5));
+
+        assert_eq!(setup.client.state, ConnectionState::Connected);
+        assert_eq!(setup.server.client_state(1), Some(ClientState::Connected { nonce, challenge: None }));
+        assert_eq!(setup.server.world.get_entities().len(), 1);
+    }
+
+    #[test]
+    fn stray_connect_request_leaves_the_connection_alone() {
+        let mut setup = setup();
+        setup.run_for(Duration::from_millis(500));
+        let Some(ClientState::Connected { nonce, .. }) = setup.server.client_state(1) else {
+            panic!("client not connected on the server");
+        };
+        let entity_id = *setup.server.networked_players.get(&1).unwrap();
+
+        // Someone else asking to connect as them, who never answers the challenge
+        setup.server.get_network().borrow_mut().send(
+            1,
+            Message {
+                handshake: Some(Handshake::ConnectRequest {
+                    colour: Colour::Red,
+                    session: None,
+                    nonce: nonce.wrapping_add(1),
+                }),
+                ..Default::default()
+            },
+        );
+        setup.run_for(Duration::from_millis(100));
+        assert!(matches!(
+            setup.server.client_state(1),
+            Some(ClientState::Connected { nonce: connected, challenge: Some(_) }) if connected == nonce
+        ));
+        assert_eq!(setup.server.networked_players.get(&1), Some(&entity_id));
+
+        setup.run_for(Duration::from_secs(5));
+        assert_eq!(setup.client.state, ConnectionState::Connected);
+        assert_eq!(setup.server.client_state(1), Some(ClientState::Connected { nonce, challenge: None }));
+        assert_eq!(setup.server.networked_players.get(&1), Some(&entity_id));
+        assert_eq!(setup.server.world.get_entities().len(), 1);
+    }
+
+    #[test]
+    fn connecting_again_starts_a_new_attempt() {
+        let mut setup = setup();
+        setup.run_for(Duration::from_millis(500));
+        let Some(ClientState::Connected { nonce, .. }) = setup.server.client_state(1) else {
+            panic!("client not connected on the server");
+        };
+        let entity_id = *setup.server.networked_players.get(&1).unwrap();
+
+        // Starting over with our session carries on with the same player
+        let server_network = setup.server.get_network();
+        setup.client.connect_to(server_network);
+        setup.run_for(Duration::from_millis(500));
+
+        assert_eq!(setup.client.state, ConnectionState::Connected);
+        assert!(matches!(
+            setup.server.client_state(1),
+            Some(ClientState::Connected { nonce: new_nonce, .. }) if new_nonce != nonce
+        ));
+        assert_eq!(setup.server.networked_players.get(&1), Some(&entity_id));
+        assert_eq!(setup.server.world.get_entities().len(), 1);
+    }
+
+    #[test]
+    fn client_gives_up_on_a_handshake_that_stops_being_answered() {
+        let mut setup = setup();
+        setup.cut_upstream_after_challenge(Duration::from_secs(4));
+        setup.run_for(Duration::from_secs(1));
+
+        assert_eq!(setup.client.state, ConnectionState::TimedOut);
+        assert_eq!(setup.server.client_state(1), None);
+    }
+
+    #[test]
+    fn client_asks_again_when_the_server_forgot_its_challenge() {
+        let mut setup = setup();
+        // Still waiting after the server has given up on it
+        setup.client.timeout = Duration::from_secs(10);
+        setup.cut_upstream_after_challenge(Duration::from_secs(4));
+        assert_eq!(setup.server.client_state(1), None);
+
+        setup.run_for(Duration::from_secs(1));
+        assert_eq!(setup.client.state, ConnectionState::Connected);
+        assert!(matches!(setup.server.client_state(1), Some(ClientState::Connected { .. })));
+    }
+
+    // The next snapshot the server sends, once it has reached the client
+    fn next_snapshot(setup: &mut Setup) -> Message {
+        setup.clock.advance(Duration::from_millis(16));
+        setup.server.update();
+        setup.clock.advance(Duration::from_millis(30));
+
+        let mut network = setup.client.network.borrow_mut();
+        std::iter::from_fn(|| network.receive())
+            .map(|(_, message)| message)
+            .filter(|message| message.snapshot_tick.is_some())
+            .last()
+            .expect("no snapshot arrived")
+    }
+
+    #[test]
+    fn full_snapshot_once_the_acked_baseline_is_forgotten() {
+        let mut setup = setup();
+        setup.run_for(Duration::from_millis(500));
+        let snapshot = next_snapshot(&mut setup);
+        assert!(snapshot.delta.is_some() && snapshot.state.is_none());
+
+        // Lose every ack until the snapshot they last acked has gone from history
+        let network = setup.server.get_network();
+        let conditions = network.borrow().sender_conditions(1);
+        network.borrow_mut().set_sender_conditions(
+            1,
+            LinkConditions {
+                loss: LossModel::Uniform { drop_rate: 1.0 },
+                ..conditions
+            },
+        );
+        setup.run_for(Duration::from_millis(16 * SNAPSHOT_HISTORY_LENGTH as u64 + 200));
+
+        let snapshot = next_snapshot(&mut setup);
+        assert!(snapshot.state.is_some() && snapshot.delta.is_none());
+        assert_eq!(setup.server.snapshot_bytes, wire::encode_message(&snapshot).len());
     }
 }
@@ -1,14 +1,20 @@
 use std::collections::HashMap;
 
-#[derive(Default, Debug, Clone, Copy)]
+// Entities are squares this wide, positioned by their top left corner
+pub const ENTITY_SIZE: f32 = 50.0;
+
+pub const MAX_HEALTH: i32 = 100;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Input {
     pub left: bool,
     pub right: bool,
     pub up: bool,
     pub down: bool,
+    pub fire: bool,
 }
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Colour {
     #[default]
     Red,
@@ -21,6 +27,9 @@ pub struct Entity {
     pub position: (f32, f32),
     pub speed: f32,
     pub colour: Colour,
+    pub health: i32,
+    // Unit direction of the last move, shots go this way
+    pub facing: (f32, f32),
 }
 
 impl Entity {
@@ -29,28 +38,61 @@ impl Entity {
             position: (0.0, 0.0),
             speed: 5.0,
             colour: Colour::Red,
+            health: MAX_HEALTH,
+            facing: (1.0, 0.0),
         }
     }
 
     pub fn integrate_input(&mut self, input: &Input) {
+        let mut direction = (0.0, 0.0);
         if input.left {
-            self.position.0 -= self.speed;
+            direction.0 -= 1.0;
         }
         if input.right {
-            self.position.0 += self.speed;
+            direction.0 += 1.0;
         }
         if input.up {
-            self.position.1 -= self.speed;
+            direction.1 -= 1.0;
         }
         if input.down {
-            self.position.1 += self.speed;
+            direction.1 += 1.0;
+        }
+
+        self.position.0 += direction.0 * self.speed;
+        self.position.1 += direction.1 * self.speed;
+
+        // Keep facing the same way when standing still
+        let length = (direction.0 * direction.0 + direction.1 * direction.1).sqrt();
+        if length > 0.0 {
+            self.facing = (direction.0 / length, direction.1 / length);
         }
     }
+
+    pub fn centre(&self) -> (f32, f32) {
+        (
+            self.position.0 + ENTITY_SIZE / 2.0,
+            self.position.1 + ENTITY_SIZE / 2.0,
+        )
+    }
+}
+
+/// Something fired that travels a little each tick until it hits or runs out
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct Projectile {
+    // Entity that fired it
+    pub owner: i32,
+    pub position: (f32, f32),
+    // How far it moves each tick
+    pub velocity: (f32, f32),
+    // Ticks before it disappears
+    pub ticks_left: i32,
 }
 
 pub struct World {
     entities: HashMap<i32, Entity>,
     latest_entity_id: i32,
+    projectiles: HashMap<i32, Projectile>,
+    latest_projectile_id: i32,
 }
 
 impl World {
@@ -58,6 +100,8 @@ impl World {
         World {
             entities: HashMap::new(),
             latest_entity_id: 0,
+            projectiles: HashMap::new(),
+            latest_projectile_id: 0,
         }
     }
 
@@ -67,6 +111,10 @@ impl World {
         self.latest_entity_id
     }
 
+    pub fn remove_entity(&mut self, entity_id: i32) -> Option<Entity> {
+        self.entities.remove(&entity_id)
+    }
+
     pub fn get_entity(&mut self, entity_id: i32) -> Option<&mut Entity> {
         self.entities.get_mut(&entity_id)
     }
@@ -79,4 +127,38 @@ impl World {
         &mut self.entities
     }
 
+    pub fn add_projectile(&mut self, projectile: Projectile) -> i32 {
+        self.latest_projectile_id += 1;
+        self.projectiles.insert(self.latest_projectile_id, projectile);
+        self.latest_projectile_id
+    }
+
+    // Add a projectile with an id given by someone else, e.g. the server
+    pub fn insert_projectile(&mut self, projectile_id: i32, projectile: Projectile) {
+        self.projectiles.insert(projectile_id, projectile);
+    }
+
+    pub fn remove_projectile(&mut self, projectile_id: i32) -> Option<Projectile> {
+        self.projectiles.remove(&projectile_id)
+    }
+
+    pub fn get_projectiles(&self) -> &HashMap<i32, Projectile> {
+        &self.projectiles
+    }
+
+    // Move every projectile along a tick, dropping any that have run out
+    pub fn update_projectiles(&mut self) {
+        self.projectiles.retain(|_, projectile| {
+            projectile.position.0 += projectile.velocity.0;
+            projectile.position.1 += projectile.velocity.1;
+            projectile.ticks_left -= 1;
+            projectile.ticks_left > 0
+        });
+    }
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
 }
\ No newline at end of file
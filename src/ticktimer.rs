@@ -1,32 +1,74 @@
-use std::time::{Duration, Instant};
+use std::{rc::Rc, time::Duration};
+
+use crate::clock::{SharedClock, SystemClock};
+
+// Enough to ride out a dropped frame or two without running away after a long stall
+const DEFAULT_MAX_TICKS_PER_FRAME: u32 = 8;
+
+/// What happens to the time left over when a frame hits the tick limit
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatchUp {
+    /// Throw it away, the simulation falls behind the clock and carries on from now
+    #[default]
+    Discard,
+    /// Keep it and work through it a frame at a time, running fast until caught up
+    Spread,
+}
 
 pub struct TickTimer {
     /// The interval at which ticks are generated
     pub tick_interval: Duration,
-    /// A timer to track the time since the last tick
-    pub timer: Instant,
+    /// Where the time comes from, a manual clock lets ticks be stepped exactly
+    clock: SharedClock,
+    /// Clock time of the last frame
+    last_frame: Duration,
     /// The current tick number
     pub current_tick: i32,
+    /// Most ticks a single call to tick will produce, 0 for no limit
+    pub max_ticks_per_frame: u32,
+    /// What to do with the time past the limit
+    pub catch_up: CatchUp,
+    /// Scales the tick interval, above 1 ticks slower and below 1 faster
+    pub dilation: f64,
     /// The time available to generate ticks
     time_available: Duration,
 }
 
 impl TickTimer {
     pub fn new(tick_interval: Duration) -> Self {
+        Self::with_clock(tick_interval, Rc::new(SystemClock::new()))
+    }
+
+    pub fn with_clock(tick_interval: Duration, clock: SharedClock) -> Self {
         TickTimer {
             tick_interval,
-            timer: Instant::now(),
+            last_frame: clock.now(),
+            clock,
             current_tick: 0,
+            max_ticks_per_frame: DEFAULT_MAX_TICKS_PER_FRAME,
+            catch_up: CatchUp::default(),
+            dilation: 1.0,
             time_available: Duration::from_secs(0),
         }
     }
 
+    // The tick interval after dilation. Never zero, or there'd be no end to
+    // the ticks when nothing limits them
+    pub fn dilated_interval(&self) -> Duration {
+        self.tick_interval
+            .mul_f64(self.dilation.max(0.0))
+            .max(Duration::from_nanos(1))
+    }
+
     pub fn tick(&mut self) -> Vec<i32> {
+        let tick_interval = self.dilated_interval();
+
         // Frame time is the elapsed time since the last frame
-        let frame_time = self.timer.elapsed();
+        let now = self.clock.now();
+        let frame_time = now.saturating_sub(self.last_frame);
 
         // Reset the timer
-        self.timer = Instant::now();
+        self.last_frame = now;
 
         // We accumlate the time given to us by frame_time
         // This then allows us to track the ticks over multiple frames
@@ -34,10 +76,19 @@ impl TickTimer {
 
         // We then generate the ticks
         let mut ticks = Vec::new();
-        while self.time_available >= self.tick_interval {
+        while self.time_available >= tick_interval {
+            if self.max_ticks_per_frame > 0 && ticks.len() as u32 >= self.max_ticks_per_frame {
+                if self.catch_up == CatchUp::Discard {
+                    // Keep the part of a tick we were already into so alpha stays smooth
+                    let remainder = self.time_available.as_nanos() % tick_interval.as_nanos();
+                    self.time_available = Duration::from_nanos(remainder as u64);
+                }
+                break;
+            }
+
             // Every tick we can produces means we can reduce the accumulator
             // by the tick interval
-            self.time_available -= self.tick_interval;
+            self.time_available -= tick_interval;
 
             ticks.push(self.current_tick);
 
@@ -47,4 +98,101 @@ impl TickTimer {
 
         ticks
     }
+
+    // How far we are through the next tick, from 0 up to 1.
+    // Rendering can use it to blend between the last two simulated states.
+    // Can be more than 1 while catching up
+    pub fn alpha(&self) -> f32 {
+        self.time_available.as_secs_f32() / self.dilated_interval().as_secs_f32()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn timer(max_ticks_per_frame: u32, catch_up: CatchUp) -> (ManualClock, TickTimer) {
+        let clock = ManualClock::new();
+        let mut timer = TickTimer::with_clock(Duration::from_millis(10), Rc::new(clock.clone()));
+        timer.max_ticks_per_frame = max_ticks_per_frame;
+        timer.catch_up = catch_up;
+        (clock, timer)
+    }
+
+    #[test]
+    fn carries_part_ticks_over_to_the_next_frame() {
+        let (clock, mut timer) = timer(0, CatchUp::Discard);
+
+        clock.advance(Duration::from_millis(25));
+        assert_eq!(timer.tick(), vec![0, 1]);
+        assert!((timer.alpha() - 0.5).abs() < 1e-4);
+
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(timer.tick(), vec![2]);
+        assert!(timer.alpha().abs() < 1e-4);
+    }
+
+    #[test]
+    fn no_limit_runs_every_tick_owed() {
+        let (clock, mut timer) = timer(0, CatchUp::Discard);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(timer.tick().len(), 100);
+    }
+
+    #[test]
+    fn discard_drops_the_time_past_the_limit() {
+        let (clock, mut timer) = timer(4, CatchUp::Discard);
+
+        // A stall worth 10 and a half ticks
+        clock.advance(Duration::from_millis(105));
+        assert_eq!(timer.tick(), vec![0, 1, 2, 3]);
+        // Only the part of a tick we were into is kept
+        assert!((timer.alpha() - 0.5).abs() < 1e-4);
+
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(timer.tick(), vec![4]);
+    }
+
+    #[test]
+    fn spread_works_through_the_backlog_over_frames() {
+        let (clock, mut timer) = timer(4, CatchUp::Spread);
+
+        clock.advance(Duration::from_millis(105));
+        assert_eq!(timer.tick(), vec![0, 1, 2, 3]);
+        assert!((timer.alpha() - 6.5).abs() < 1e-4);
+
+        // Running fast until caught up, then back to one a frame
+        let ticks: Vec<usize> = (0..3)
+            .map(|_| {
+                clock.advance(Duration::from_millis(10));
+                timer.tick().len()
+            })
+            .collect();
+        assert_eq!(ticks, vec![4, 4, 1]);
+        assert_eq!(timer.current_tick, 13);
+    }
+
+    #[test]
+    fn dilation_stretches_and_squeezes_the_interval() {
+        let (clock, mut timer) = timer(0, CatchUp::Discard);
+
+        timer.dilation = 2.0;
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(timer.tick().len(), 5);
+
+        timer.dilation = 0.5;
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(timer.tick().len(), 20);
+    }
+
+    #[test]
+    fn zero_dilation_still_finishes_the_frame() {
+        let (clock, mut timer) = timer(0, CatchUp::Discard);
+
+        timer.dilation = 0.0;
+        clock.advance(Duration::from_micros(10));
+        assert_eq!(timer.tick().len(), 10_000);
+    }
 }
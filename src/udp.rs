@@ -0,0 +1,397 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    rc::Rc,
+};
+
+use crate::{
+    handshake::Handshake,
+    net::{Message, SharedTransport, Transport},
+    wire::{self, DecodeError},
+};
+
+// Largest datagram we will try to read
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+struct Socket {
+    socket: UdpSocket,
+    // Map of remote addresses to the network id that sent from them
+    peers: HashMap<SocketAddr, i32>,
+    // Addresses claiming an id that's already bound to another address. Only
+    // their handshakes get through until one of them is bound in its place
+    unverified: HashMap<SocketAddr, i32>,
+    // Messages read from the socket but not yet received, with where they came from
+    received: VecDeque<(SocketAddr, i32, Message)>,
+    // Where the last message received came from, for replying to it
+    last_received: Option<(i32, SocketAddr)>,
+    // Reused for every read so polling doesn't allocate
+    buffer: Vec<u8>,
+}
+
+/// Transport over a real non-blocking UDP socket.
+///
+/// `bind` gives you the inbox for the local socket. Use `to` or `peer` to get a
+/// handle that sends from the same socket to a remote inbox, that way the other
+/// end sees our address and can map it to our id.
+#[derive(Clone)]
+pub struct UdpTransport {
+    socket: Rc<RefCell<Socket>>,
+    // Where messages sent through this handle go
+    remote: SocketAddr,
+}
+
+impl UdpTransport {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let local_addr = socket.local_addr()?;
+
+        Ok(UdpTransport {
+            socket: Rc::new(RefCell::new(Socket {
+                socket,
+                peers: HashMap::new(),
+                unverified: HashMap::new(),
+                received: VecDeque::new(),
+                last_received: None,
+                buffer: vec![0; MAX_DATAGRAM_SIZE],
+            })),
+            remote: local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.borrow().socket.local_addr()
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    // Handle for sending to a remote address from this socket
+    pub fn to(&self, remote: SocketAddr) -> Self {
+        UdpTransport {
+            socket: Rc::clone(&self.socket),
+            remote,
+        }
+    }
+
+    // Handle for sending back to a network id we have received from
+    pub fn peer(&self, id: i32) -> Option<Self> {
+        self.peer_addr(id).map(|addr| self.to(addr))
+    }
+
+    pub fn peer_addr(&self, id: i32) -> Option<SocketAddr> {
+        self.socket
+            .borrow()
+            .peers
+            .iter()
+            .find(|(_, peer_id)| **peer_id == id)
+            .map(|(addr, _)| *addr)
+    }
+}
+
+impl Transport for UdpTransport {
+    // Send a message along with who sent it
+    fn send(&mut self, sender_id: i32, message: Message) {
+        let datagram = encode(sender_id, &message);
+
+        // UDP is fire and forget, a full send buffer is the same as a dropped packet
+        let _ = self.socket.borrow().socket.send_to(&datagram, self.remote);
+    }
+
+    // Returns the next message along with sender_id who sent the message
+    fn receive(&mut self) -> Option<(i32, Message)> {
+        if self.socket.borrow().received.is_empty() {
+            self.poll();
+        }
+
+        let mut socket = self.socket.borrow_mut();
+        let (addr, sender_id, message) = socket.received.pop_front()?;
+        socket.last_received = Some((sender_id, addr));
+        Some((sender_id, message))
+    }
+
+    // Back to where the message just received came from, which for a
+    // handshake can be an address they haven't been bound to yet
+    fn reply_to(&self, sender_id: i32) -> Option<SharedTransport> {
+        let addr = match self.socket.borrow().last_received {
+            Some((id, addr)) if id == sender_id => Some(addr),
+            _ => self.peer_addr(sender_id),
+        };
+        addr.map(|addr| Rc::new(RefCell::new(self.to(addr))) as SharedTransport)
+    }
+
+    // Move the id over to the address the last message from them came from
+    fn bind_sender(&mut self, sender_id: i32) {
+        let mut socket = self.socket.borrow_mut();
+        let Some((id, addr)) = socket.last_received else {
+            return;
+        };
+        if id != sender_id {
+            return;
+        }
+
+        socket.unverified.remove(&addr);
+        socket.peers.retain(|_, peer_id| *peer_id != sender_id);
+        socket.peers.insert(addr, sender_id);
+    }
+
+    // Drain everything waiting on the socket
+    fn poll(&mut self) {
+        let mut socket = self.socket.borrow_mut();
+        let Socket {
+            socket: udp_socket,
+            peers,
+            unverified,
+            received,
+            buffer,
+            ..
+        } = &mut *socket;
+
+        loop {
+            let (size, addr) = match udp_socket.recv_from(buffer) {
+                Ok(received) => received,
+                // ICMP port unreachable from a peer that went away, there may
+                // still be packets from everyone else behind it
+                Err(e) if matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused) => continue,
+                // Nothing left to read, or something wrong with the socket that
+                // trying again straight away won't fix
+                Err(_) => break,
+            };
+
+            // Anything we can't read is treated as a dropped packet
+            let Ok((sender_id, message)) = decode(&buffer[..size]) else {
+                continue;
+            };
+
+            // The first packet from an address tells us who is there, after that
+            // we trust the address rather than the id in the packet
+            let sender_id = match peers.get(&addr) {
+                Some(peer_id) => *peer_id,
+                None => {
+                    // Same id from a new address, e.g. the peer restarted or
+                    // someone is pretending to be them. Nothing but asking to
+                    // connect gets through until they're bound to it
+                    let sender_id = *unverified.entry(addr).or_insert(sender_id);
+                    if peers.values().any(|peer_id| *peer_id == sender_id) {
+                        if !matches!(
+                            message.handshake,
+                            Some(Handshake::ConnectRequest { .. } | Handshake::ChallengeResponse { .. })
+                        ) {
+                            continue;
+                        }
+                    } else {
+                        unverified.remove(&addr);
+                        peers.insert(addr, sender_id);
+                    }
+                    sender_id
+                }
+            };
+
+            received.push_back((addr, sender_id, message));
+        }
+    }
+}
+
+// Datagrams are the sender id, big endian like the rest of the wire format,
+// followed by the encoded message
+fn encode(sender_id: i32, message: &Message) -> Vec<u8> {
+    let mut bytes = sender_id.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&wire::encode_message(message));
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<(i32, Message), DecodeError> {
+    if bytes.len() < 4 {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (sender_id, message) = bytes.split_at(4);
+    let sender_id = i32::from_be_bytes([sender_id[0], sender_id[1], sender_id[2], sender_id[3]]);
+
+    Ok((sender_id, wire::decode_message(message)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{client::Client, handshake::ConnectionState, server::Server, sim::Colour};
+
+    fn bind() -> UdpTransport {
+        UdpTransport::bind("127.0.0.1:0").expect("failed to bind UDP socket")
+    }
+
+    // Loopback is quick but not instant, give the datagram a moment to land
+    fn receive(transport: &mut UdpTransport) -> (i32, Message) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            if let Some(received) = transport.receive() {
+                return received;
+            }
+            assert!(Instant::now() < deadline, "nothing arrived on {:?}", transport.local_addr());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn message(sequence: i32) -> Message {
+        Message {
+            sequence,
+            ..Default::default()
+        }
+    }
+
+    fn connect_request() -> Message {
+        Message {
+            handshake: Some(Handshake::ConnectRequest {
+                colour: Colour::Red,
+                session: None,
+                nonce: 1,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sends_both_ways_and_maps_addresses_to_ids() {
+        let mut server = bind();
+        let mut client = bind();
+        let server_addr = server.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        client.to(server_addr).send(1, message(10));
+        let (sender_id, received) = receive(&mut server);
+        assert_eq!(sender_id, 1);
+        assert_eq!(received.sequence, 10);
+        assert_eq!(server.peer_addr(1), Some(client_addr));
+
+        // Reply through the handle for the id we just heard from
+        let mut reply = server.peer(1).expect("no peer for id 1");
+        assert_eq!(reply.remote_addr(), client_addr);
+        reply.send(0, message(20));
+        let (sender_id, received) = receive(&mut client);
+        assert_eq!(sender_id, 0);
+        assert_eq!(received.sequence, 20);
+        assert_eq!(client.peer_addr(0), Some(server_addr));
+    }
+
+    #[test]
+    fn trusts_the_address_over_the_id_in_the_packet() {
+        let mut server = bind();
+        let client = bind();
+        let server_addr = server.local_addr().unwrap();
+
+        client.to(server_addr).send(1, message(1));
+        assert_eq!(receive(&mut server).0, 1);
+
+        // Claiming to be someone else from an address we already know
+        client.to(server_addr).send(2, message(2));
+        assert_eq!(receive(&mut server).0, 1);
+        assert_eq!(server.peer_addr(2), None);
+    }
+
+    #[test]
+    fn same_id_from_a_new_address_waits_to_be_bound() {
+        let mut server = bind();
+        let old_client = bind();
+        let mut new_client = bind();
+        let server_addr = server.local_addr().unwrap();
+        let old_addr = old_client.local_addr().unwrap();
+        let new_addr = new_client.local_addr().unwrap();
+
+        old_client.to(server_addr).send(1, message(1));
+        assert_eq!(receive(&mut server).0, 1);
+        assert_eq!(server.peer_addr(1), Some(old_addr));
+
+        // e.g. the client restarted on another port. Only asking to connect
+        // gets through, and gets answered where it came from
+        new_client.to(server_addr).send(1, message(2));
+        new_client.to(server_addr).send(1, connect_request());
+        let (sender_id, received) = receive(&mut server);
+        assert_eq!(sender_id, 1);
+        assert!(received.handshake.is_some());
+        assert_eq!(server.peer_addr(1), Some(old_addr));
+        let reply = server.reply_to(1).expect("no reply for id 1");
+        reply.borrow_mut().send(0, message(3));
+        assert_eq!(receive(&mut new_client).1.sequence, 3);
+
+        // Once they've proved who they are the id is theirs
+        server.bind_sender(1);
+        assert_eq!(server.peer_addr(1), Some(new_addr));
+        assert_eq!(server.socket.borrow().peers.len(), 1);
+        old_client.to(server_addr).send(1, message(4));
+        new_client.to(server_addr).send(1, message(5));
+        assert_eq!(receive(&mut server).1.sequence, 5);
+    }
+
+    #[test]
+    fn ignores_datagrams_that_are_not_messages() {
+        let mut server = bind();
+        let client = bind();
+        let server_addr = server.local_addr().unwrap();
+
+        client.socket.borrow().socket.send_to(&[1, 2], server_addr).unwrap();
+        client.to(server_addr).send(1, message(3));
+        assert_eq!(receive(&mut server).1.sequence, 3);
+    }
+
+    #[test]
+    fn server_answers_a_client_it_was_never_told_about() {
+        let server_socket = bind();
+        let client_socket = bind();
+        let server_addr = server_socket.local_addr().unwrap();
+
+        let mut server = Server::with_network(16, Rc::new(RefCell::new(server_socket)));
+        let mut client = Client::with_network(1, 16, Rc::new(RefCell::new(client_socket.clone())));
+        client.keyboard_input_enabled = false;
+        client.connect_to(Rc::new(RefCell::new(client_socket.to(server_addr))));
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while client.state != ConnectionState::Connected && Instant::now() < deadline {
+            client.update();
+            server.update();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(client.state, ConnectionState::Connected);
+        assert_eq!(server.world.get_entities().len(), 1);
+    }
+
+    #[test]
+    fn connect_request_from_another_address_leaves_the_route_alone() {
+        let server_socket = bind();
+        let client_socket = bind();
+        let impostor = bind();
+        let server_addr = server_socket.local_addr().unwrap();
+        let client_addr = client_socket.local_addr().unwrap();
+
+        let mut server = Server::with_network(16, Rc::new(RefCell::new(server_socket.clone())));
+        let mut client = Client::with_network(1, 16, Rc::new(RefCell::new(client_socket.clone())));
+        client.keyboard_input_enabled = false;
+        client.connect_to(Rc::new(RefCell::new(client_socket.to(server_addr))));
+
+        let run_for = |client: &mut Client, server: &mut Server, duration: Duration| {
+            let deadline = Instant::now() + duration;
+            while Instant::now() < deadline {
+                client.update();
+                server.update();
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        run_for(&mut client, &mut server, Duration::from_millis(300));
+        assert_eq!(client.state, ConnectionState::Connected);
+
+        // Asking to connect as them from somewhere else doesn't get their packets
+        impostor.to(server_addr).send(1, connect_request());
+        run_for(&mut client, &mut server, Duration::from_millis(300));
+
+        assert_eq!(client.state, ConnectionState::Connected);
+        assert_eq!(server_socket.peer_addr(1), Some(client_addr));
+        assert_eq!(server.world.get_entities().len(), 1);
+    }
+}
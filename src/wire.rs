@@ -0,0 +1,1244 @@
+use std::fmt;
+
+use crate::{
+    bits::{BitReader, BitWriter, Quantization},
+    combat::CombatEvent,
+    handshake::{DenyReason, Handshake},
+    net::{Channel, Delta, Message, PacketHeader, Pong, State, StateDelta},
+    sim::{Colour, Input, Projectile},
+};
+
+/// Version written at the start of every encoded message.
+/// Bump this whenever the layout below changes.
+pub const WIRE_VERSION: u8 = 16;
+
+// Tags for which optional fields follow the header
+const TAG_STATE: u32 = 1 << 0;
+const TAG_INPUT: u32 = 1 << 1;
+const TAG_SNAPSHOT_TICK: u32 = 1 << 2;
+const TAG_DELTA: u32 = 1 << 3;
+const TAG_SNAPSHOT_ACK: u32 = 1 << 4;
+const TAG_PACKET: u32 = 1 << 5;
+const TAG_CHANNEL: u32 = 1 << 6;
+const TAG_PING: u32 = 1 << 7;
+const TAG_PONG: u32 = 1 << 8;
+const TAG_INPUT_LEAD: u32 = 1 << 9;
+const TAG_PREVIOUS_INPUTS: u32 = 1 << 10;
+const TAG_VIEW_TICK: u32 = 1 << 11;
+const TAG_EVENTS: u32 = 1 << 12;
+const TAG_DESPAWNS: u32 = 1 << 13;
+const TAG_HANDSHAKE: u32 = 1 << 14;
+const TAG_BITS: u32 = 15;
+
+// View ticks are sent in fractions of a tick
+const VIEW_TICK_STEPS: f32 = 16.0;
+
+/// Settings both ends need to agree on to read each other's messages
+#[derive(Debug, Clone, Copy)]
+pub struct WireConfig {
+    // Range and precision positions are quantized to on both axes
+    pub position: Quantization,
+}
+
+impl Default for WireConfig {
+    fn default() -> Self {
+        WireConfig {
+            // Comfortably bigger than the demo world, at 20 bits per axis
+            position: Quantization {
+                min: -4096.0,
+                max: 4096.0,
+                precision: 0.01,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Ran out of bytes part way through a field
+    UnexpectedEnd,
+    /// Encoded by a different version of the protocol
+    UnsupportedVersion(u8),
+    /// Varint longer than the 32 bits it's meant to hold
+    InvalidVarint,
+    InvalidColour(u8),
+    InvalidChannel(u8),
+    InvalidEvent(u8),
+    InvalidHandshake(u8),
+    InvalidDenyReason(u8),
+    /// Bytes left over after the message was decoded
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of message"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported wire version {} (expected {})", version, WIRE_VERSION)
+            }
+            DecodeError::InvalidVarint => write!(f, "varint overflows 32 bits"),
+            DecodeError::InvalidColour(colour) => write!(f, "invalid colour {}", colour),
+            DecodeError::InvalidChannel(channel) => write!(f, "invalid channel {}", channel),
+            DecodeError::InvalidEvent(kind) => write!(f, "invalid combat event {}", kind),
+            DecodeError::InvalidHandshake(kind) => write!(f, "invalid handshake {}", kind),
+            DecodeError::InvalidDenyReason(reason) => write!(f, "invalid deny reason {}", reason),
+            DecodeError::TrailingBytes(count) => write!(f, "{} trailing bytes", count),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// Message layout, packed at the bit level with a BitWriter
+//
+// version: 8 bits
+// tags: 15 bits, one per optional field present
+// sequence: zigzag varint
+// input (TAG_INPUT): 5 bits, left, right, up, down, fire
+// state (TAG_STATE): varint count, then count states
+// snapshot_tick (TAG_SNAPSHOT_TICK): zigzag varint
+// delta (TAG_DELTA): see below
+// snapshot_ack (TAG_SNAPSHOT_ACK): zigzag varint
+// packet (TAG_PACKET): 16 bit sequence, 1 bit ack present, then 16 bit ack and 32 ack bits
+// channel (TAG_CHANNEL): 2 bit channel, 16 bit channel_sequence. Absent means unreliable
+// ping (TAG_PING): 32 bits
+// pong (TAG_PONG): 32 bit ping_time, zigzag varint server_tick, varint tick_interval_ms
+// input_lead (TAG_INPUT_LEAD): zigzag varint
+// previous_inputs (TAG_PREVIOUS_INPUTS): varint count, then for each, newest first
+//   1 bit if its sequence is one before the input ahead of it, otherwise
+//   zigzag varint of how far behind that it is
+//   1 bit if the keys are the same as the input ahead of it, otherwise 5 bits as in input
+// Clients send input every tick so a run of the same keys costs 2 bits an input
+// view_tick (TAG_VIEW_TICK): zigzag varint of how far it is behind sequence, in 1/16ths of a tick
+// events (TAG_EVENTS): varint count, then count events
+// despawns (TAG_DESPAWNS): varint count, then each entity_id as zigzag varint
+// handshake (TAG_HANDSHAKE): see below
+//
+// State layout
+// entity_id: zigzag varint
+// x, y: quantized to WireConfig::position
+// colour: 2 bits
+//
+// Event layout
+// kind: 2 bits, then for each kind
+//   shot: zigzag varint shooter, from and to each x, y as in State
+//   projectile fired: zigzag varint projectile_id, zigzag varint owner,
+//     position and velocity each x, y as in State, varint ticks_left
+//   hit: zigzag varint shooter and target, 1 bit projectile present then
+//     zigzag varint projectile_id, zigzag varint health
+//   kill: zigzag varint shooter and target
+//
+// Handshake layout
+// kind: 3 bits, then for each kind
+//   connect request: colour as in State, 1 bit session present then 32 bit session,
+//     32 bit nonce
+//   challenge: 32 bit salt, 32 bit nonce
+//   challenge response: 32 bit salt
+//   accept: zigzag varint entity_id, 32 bit session
+//   deny: 2 bit reason
+//   disconnect: nothing
+//
+// Delta layout
+// baseline_tick: zigzag varint
+// varint count of changed entities, then for each
+//   entity_id: zigzag varint
+//   1 bit position changed, then x, y as in State
+//   1 bit colour changed, then colour as in State
+// varint count of removed entities, then each entity_id as zigzag varint
+//
+// tests::demo_snapshot_sizes checks bit packing and deltas still pay for
+// themselves on the demo world
+pub fn encode_message(message: &Message) -> Vec<u8> {
+    encode_message_with(message, &WireConfig::default())
+}
+
+pub fn decode_message(bytes: &[u8]) -> Result<Message, DecodeError> {
+    decode_message_with(bytes, &WireConfig::default())
+}
+
+pub fn encode_message_with(message: &Message, config: &WireConfig) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write_bits(WIRE_VERSION as u32, 8);
+
+    let mut tags = 0;
+    if message.state.is_some() {
+        tags |= TAG_STATE;
+    }
+    if message.input.is_some() {
+        tags |= TAG_INPUT;
+    }
+    if message.snapshot_tick.is_some() {
+        tags |= TAG_SNAPSHOT_TICK;
+    }
+    if message.delta.is_some() {
+        tags |= TAG_DELTA;
+    }
+    if message.snapshot_ack.is_some() {
+        tags |= TAG_SNAPSHOT_ACK;
+    }
+    if message.packet.is_some() {
+        tags |= TAG_PACKET;
+    }
+    if message.channel_sequence.is_some() {
+        tags |= TAG_CHANNEL;
+    }
+    if message.ping.is_some() {
+        tags |= TAG_PING;
+    }
+    if message.pong.is_some() {
+        tags |= TAG_PONG;
+    }
+    if message.input_lead.is_some() {
+        tags |= TAG_INPUT_LEAD;
+    }
+    if !message.previous_inputs.is_empty() {
+        tags |= TAG_PREVIOUS_INPUTS;
+    }
+    if message.view_tick.is_some() {
+        tags |= TAG_VIEW_TICK;
+    }
+    if !message.events.is_empty() {
+        tags |= TAG_EVENTS;
+    }
+    if !message.despawns.is_empty() {
+        tags |= TAG_DESPAWNS;
+    }
+    if message.handshake.is_some() {
+        tags |= TAG_HANDSHAKE;
+    }
+    writer.write_bits(tags, TAG_BITS);
+
+    writer.write_signed_varint(message.sequence);
+
+    if let Some(input) = &message.input {
+        encode_input(input, &mut writer);
+    }
+
+    if let Some(states) = &message.state {
+        writer.write_varint(states.len() as u32);
+        for state in states {
+            encode_state(state, config, &mut writer);
+        }
+    }
+
+    if let Some(snapshot_tick) = message.snapshot_tick {
+        writer.write_signed_varint(snapshot_tick);
+    }
+
+    if let Some(delta) = &message.delta {
+        encode_delta(delta, config, &mut writer);
+    }
+
+    if let Some(snapshot_ack) = message.snapshot_ack {
+        writer.write_signed_varint(snapshot_ack);
+    }
+
+    if let Some(packet) = message.packet {
+        writer.write_bits(packet.sequence as u32, 16);
+        writer.write_bool(packet.ack.is_some());
+        if let Some(ack) = packet.ack {
+            writer.write_bits(ack as u32, 16);
+            writer.write_bits(packet.ack_bits, 32);
+        }
+    }
+
+    if let Some(channel_sequence) = message.channel_sequence {
+        writer.write_bits(encode_channel(message.channel), 2);
+        writer.write_bits(channel_sequence as u32, 16);
+    }
+
+    if let Some(ping) = message.ping {
+        writer.write_bits(ping, 32);
+    }
+
+    if let Some(pong) = message.pong {
+        writer.write_bits(pong.ping_time, 32);
+        writer.write_signed_varint(pong.server_tick);
+        writer.write_varint(pong.tick_interval_ms);
+    }
+
+    if let Some(input_lead) = message.input_lead {
+        writer.write_signed_varint(input_lead);
+    }
+
+    if !message.previous_inputs.is_empty() {
+        writer.write_varint(message.previous_inputs.len() as u32);
+
+        // Each input is written relative to the one ahead of it
+        let mut ahead = (message.sequence, message.input.unwrap_or_default());
+        for &(sequence, input) in &message.previous_inputs {
+            let gap = ahead.0.wrapping_sub(sequence);
+            writer.write_bool(gap == 1);
+            if gap != 1 {
+                writer.write_signed_varint(gap);
+            }
+
+            writer.write_bool(input == ahead.1);
+            if input != ahead.1 {
+                encode_input(&input, &mut writer);
+            }
+
+            ahead = (sequence, input);
+        }
+    }
+
+    if let Some(view_tick) = message.view_tick {
+        let behind = (message.sequence as f32 - view_tick) * VIEW_TICK_STEPS;
+        writer.write_signed_varint(behind.round() as i32);
+    }
+
+    if !message.events.is_empty() {
+        writer.write_varint(message.events.len() as u32);
+        for event in &message.events {
+            encode_event(event, config, &mut writer);
+        }
+    }
+
+    if !message.despawns.is_empty() {
+        writer.write_varint(message.despawns.len() as u32);
+        for entity_id in &message.despawns {
+            writer.write_signed_varint(*entity_id);
+        }
+    }
+
+    if let Some(handshake) = &message.handshake {
+        encode_handshake(handshake, &mut writer);
+    }
+
+    writer.finish()
+}
+
+pub fn decode_message_with(bytes: &[u8], config: &WireConfig) -> Result<Message, DecodeError> {
+    let mut reader = BitReader::new(bytes);
+
+    let version = reader.read_bits(8)? as u8;
+    if version != WIRE_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let tags = reader.read_bits(TAG_BITS)?;
+
+    let sequence = reader.read_signed_varint()?;
+
+    let input = if tags & TAG_INPUT != 0 {
+        Some(decode_input(&mut reader)?)
+    } else {
+        None
+    };
+
+    let state = if tags & TAG_STATE != 0 {
+        let count = reader.read_varint()?;
+        // Don't trust the count for the allocation, every state is at least a byte
+        let mut states = Vec::with_capacity((count as usize).min(bytes.len()));
+        for _ in 0..count {
+            states.push(decode_state(&mut reader, config)?);
+        }
+        Some(states)
+    } else {
+        None
+    };
+
+    let snapshot_tick = if tags & TAG_SNAPSHOT_TICK != 0 {
+        Some(reader.read_signed_varint()?)
+    } else {
+        None
+    };
+
+    let delta = if tags & TAG_DELTA != 0 {
+        Some(decode_delta(&mut reader, config)?)
+    } else {
+        None
+    };
+
+    let snapshot_ack = if tags & TAG_SNAPSHOT_ACK != 0 {
+        Some(reader.read_signed_varint()?)
+    } else {
+        None
+    };
+
+    let packet = if tags & TAG_PACKET != 0 {
+        let sequence = reader.read_bits(16)? as u16;
+        let (ack, ack_bits) = if reader.read_bool()? {
+            (Some(reader.read_bits(16)? as u16), reader.read_bits(32)?)
+        } else {
+            (None, 0)
+        };
+        Some(PacketHeader {
+            sequence,
+            ack,
+            ack_bits,
+        })
+    } else {
+        None
+    };
+
+    let (channel, channel_sequence) = if tags & TAG_CHANNEL != 0 {
+        let channel = decode_channel(reader.read_bits(2)?)?;
+        (channel, Some(reader.read_bits(16)? as u16))
+    } else {
+        (Channel::Unreliable, None)
+    };
+
+    let ping = if tags & TAG_PING != 0 {
+        Some(reader.read_bits(32)?)
+    } else {
+        None
+    };
+
+    let pong = if tags & TAG_PONG != 0 {
+        Some(Pong {
+            ping_time: reader.read_bits(32)?,
+            server_tick: reader.read_signed_varint()?,
+            tick_interval_ms: reader.read_varint()?,
+        })
+    } else {
+        None
+    };
+
+    let input_lead = if tags & TAG_INPUT_LEAD != 0 {
+        Some(reader.read_signed_varint()?)
+    } else {
+        None
+    };
+
+    let mut previous_inputs = Vec::new();
+    if tags & TAG_PREVIOUS_INPUTS != 0 {
+        let count = reader.read_varint()?;
+        previous_inputs.reserve((count as usize).min(reader.remaining_bytes()));
+
+        let mut ahead = (sequence, input.unwrap_or_default());
+        for _ in 0..count {
+            let gap = if reader.read_bool()? {
+                1
+            } else {
+                reader.read_signed_varint()?
+            };
+            let keys = if reader.read_bool()? {
+                ahead.1
+            } else {
+                decode_input(&mut reader)?
+            };
+
+            ahead = (ahead.0.wrapping_sub(gap), keys);
+            previous_inputs.push(ahead);
+        }
+    }
+
+    let view_tick = if tags & TAG_VIEW_TICK != 0 {
+        let behind = reader.read_signed_varint()? as f32 / VIEW_TICK_STEPS;
+        Some(sequence as f32 - behind)
+    } else {
+        None
+    };
+
+    let mut events = Vec::new();
+    if tags & TAG_EVENTS != 0 {
+        let count = reader.read_varint()?;
+        events.reserve((count as usize).min(reader.remaining_bytes()));
+        for _ in 0..count {
+            events.push(decode_event(&mut reader, config)?);
+        }
+    }
+
+    let mut despawns = Vec::new();
+    if tags & TAG_DESPAWNS != 0 {
+        let count = reader.read_varint()?;
+        despawns.reserve((count as usize).min(reader.remaining_bytes()));
+        for _ in 0..count {
+            despawns.push(reader.read_signed_varint()?);
+        }
+    }
+
+    let handshake = if tags & TAG_HANDSHAKE != 0 {
+        Some(decode_handshake(&mut reader)?)
+    } else {
+        None
+    };
+
+    if reader.remaining_bytes() > 0 {
+        return Err(DecodeError::TrailingBytes(reader.remaining_bytes()));
+    }
+
+    Ok(Message {
+        sequence,
+        state,
+        input,
+        snapshot_tick,
+        delta,
+        snapshot_ack,
+        packet,
+        channel,
+        channel_sequence,
+        ping,
+        pong,
+        input_lead,
+        previous_inputs,
+        view_tick,
+        events,
+        despawns,
+        handshake,
+    })
+}
+
+fn encode_input(input: &Input, writer: &mut BitWriter) {
+    writer.write_bool(input.left);
+    writer.write_bool(input.right);
+    writer.write_bool(input.up);
+    writer.write_bool(input.down);
+    writer.write_bool(input.fire);
+}
+
+fn decode_input(reader: &mut BitReader) -> Result<Input, DecodeError> {
+    Ok(Input {
+        left: reader.read_bool()?,
+        right: reader.read_bool()?,
+        up: reader.read_bool()?,
+        down: reader.read_bool()?,
+        fire: reader.read_bool()?,
+    })
+}
+
+fn encode_point(point: (f32, f32), config: &WireConfig, writer: &mut BitWriter) {
+    let position_bits = config.position.bits();
+    writer.write_bits(config.position.quantize(point.0), position_bits);
+    writer.write_bits(config.position.quantize(point.1), position_bits);
+}
+
+fn decode_point(reader: &mut BitReader, config: &WireConfig) -> Result<(f32, f32), DecodeError> {
+    let position_bits = config.position.bits();
+    let x = config.position.dequantize(reader.read_bits(position_bits)?);
+    let y = config.position.dequantize(reader.read_bits(position_bits)?);
+    Ok((x, y))
+}
+
+fn encode_event(event: &CombatEvent, config: &WireConfig, writer: &mut BitWriter) {
+    match *event {
+        CombatEvent::Shot { shooter, from, to } => {
+            writer.write_bits(0, 2);
+            writer.write_signed_varint(shooter);
+            encode_point(from, config, writer);
+            encode_point(to, config, writer);
+        }
+        CombatEvent::ProjectileFired {
+            projectile_id,
+            projectile,
+        } => {
+            writer.write_bits(1, 2);
+            writer.write_signed_varint(projectile_id);
+            writer.write_signed_varint(projectile.owner);
+            encode_point(projectile.position, config, writer);
+            encode_point(projectile.velocity, config, writer);
+            writer.write_varint(projectile.ticks_left.max(0) as u32);
+        }
+        CombatEvent::Hit {
+            shooter,
+            target,
+            projectile,
+            health,
+        } => {
+            writer.write_bits(2, 2);
+            writer.write_signed_varint(shooter);
+            writer.write_signed_varint(target);
+            writer.write_bool(projectile.is_some());
+            if let Some(projectile_id) = projectile {
+                writer.write_signed_varint(projectile_id);
+            }
+            writer.write_signed_varint(health);
+        }
+        CombatEvent::Kill { shooter, target } => {
+            writer.write_bits(3, 2);
+            writer.write_signed_varint(shooter);
+            writer.write_signed_varint(target);
+        }
+    }
+}
+
+fn decode_event(reader: &mut BitReader, config: &WireConfig) -> Result<CombatEvent, DecodeError> {
+    let kind = reader.read_bits(2)?;
+    let event = match kind {
+        0 => CombatEvent::Shot {
+            shooter: reader.read_signed_varint()?,
+            from: decode_point(reader, config)?,
+            to: decode_point(reader, config)?,
+        },
+        1 => {
+            let projectile_id = reader.read_signed_varint()?;
+            let projectile = Projectile {
+                owner: reader.read_signed_varint()?,
+                position: decode_point(reader, config)?,
+                velocity: decode_point(reader, config)?,
+                ticks_left: reader.read_varint()? as i32,
+            };
+            CombatEvent::ProjectileFired {
+                projectile_id,
+                projectile,
+            }
+        }
+        2 => {
+            let shooter = reader.read_signed_varint()?;
+            let target = reader.read_signed_varint()?;
+            let projectile = if reader.read_bool()? {
+                Some(reader.read_signed_varint()?)
+            } else {
+                None
+            };
+            CombatEvent::Hit {
+                shooter,
+                target,
+                projectile,
+                health: reader.read_signed_varint()?,
+            }
+        }
+        3 => CombatEvent::Kill {
+            shooter: reader.read_signed_varint()?,
+            target: reader.read_signed_varint()?,
+        },
+        _ => return Err(DecodeError::InvalidEvent(kind as u8)),
+    };
+    Ok(event)
+}
+
+fn encode_handshake(handshake: &Handshake, writer: &mut BitWriter) {
+    match *handshake {
+        Handshake::ConnectRequest { colour, session, nonce } => {
+            writer.write_bits(0, 3);
+            writer.write_bits(encode_colour(colour), 2);
+            writer.write_bool(session.is_some());
+            if let Some(session) = session {
+                writer.write_bits(session, 32);
+            }
+            writer.write_bits(nonce, 32);
+        }
+        Handshake::Challenge { salt, nonce } => {
+            writer.write_bits(1, 3);
+            writer.write_bits(salt, 32);
+            writer.write_bits(nonce, 32);
+        }
+        Handshake::ChallengeResponse { salt } => {
+            writer.write_bits(2, 3);
+            writer.write_bits(salt, 32);
+        }
+        Handshake::Accept { entity_id, session } => {
+            writer.write_bits(3, 3);
+            writer.write_signed_varint(entity_id);
+            writer.write_bits(session, 32);
+        }
+        Handshake::Deny { reason } => {
+            writer.write_bits(4, 3);
+            writer.write_bits(encode_deny_reason(reason), 2);
+        }
+        Handshake::Disconnect => {
+            writer.write_bits(5, 3);
+        }
+    }
+}
+
+fn decode_handshake(reader: &mut BitReader) -> Result<Handshake, DecodeError> {
+    let kind = reader.read_bits(3)?;
+    let handshake = match kind {
+        0 => {
+            let colour = decode_colour(reader.read_bits(2)?)?;
+            let session = if reader.read_bool()? {
+                Some(reader.read_bits(32)?)
+            } else {
+                None
+            };
+            Handshake::ConnectRequest {
+                colour,
+                session,
+                nonce: reader.read_bits(32)?,
+            }
+        }
+        1 => Handshake::Challenge {
+            salt: reader.read_bits(32)?,
+            nonce: reader.read_bits(32)?,
+        },
+        2 => Handshake::ChallengeResponse {
+            salt: reader.read_bits(32)?,
+        },
+        3 => Handshake::Accept {
+            entity_id: reader.read_signed_varint()?,
+            session: reader.read_bits(32)?,
+        },
+        4 => Handshake::Deny {
+            reason: decode_deny_reason(reader.read_bits(2)?)?,
+        },
+        5 => Handshake::Disconnect,
+        _ => return Err(DecodeError::InvalidHandshake(kind as u8)),
+    };
+    Ok(handshake)
+}
+
+fn encode_deny_reason(reason: DenyReason) -> u32 {
+    match reason {
+        DenyReason::ServerFull => 0,
+        DenyReason::ChallengeExpired => 1,
+    }
+}
+
+fn decode_deny_reason(reason: u32) -> Result<DenyReason, DecodeError> {
+    match reason {
+        0 => Ok(DenyReason::ServerFull),
+        1 => Ok(DenyReason::ChallengeExpired),
+        _ => Err(DecodeError::InvalidDenyReason(reason as u8)),
+    }
+}
+
+fn encode_state(state: &State, config: &WireConfig, writer: &mut BitWriter) {
+    let position_bits = config.position.bits();
+
+    writer.write_signed_varint(state.entity_id);
+    writer.write_bits(config.position.quantize(state.position.0), position_bits);
+    writer.write_bits(config.position.quantize(state.position.1), position_bits);
+    writer.write_bits(encode_colour(state.colour), 2);
+}
+
+fn decode_state(reader: &mut BitReader, config: &WireConfig) -> Result<State, DecodeError> {
+    let position_bits = config.position.bits();
+
+    let entity_id = reader.read_signed_varint()?;
+    let x = config.position.dequantize(reader.read_bits(position_bits)?);
+    let y = config.position.dequantize(reader.read_bits(position_bits)?);
+    let colour = decode_colour(reader.read_bits(2)?)?;
+
+    Ok(State {
+        entity_id,
+        position: (x, y),
+        colour,
+    })
+}
+
+fn encode_delta(delta: &Delta, config: &WireConfig, writer: &mut BitWriter) {
+    let position_bits = config.position.bits();
+
+    writer.write_signed_varint(delta.baseline_tick);
+
+    writer.write_varint(delta.changed.len() as u32);
+    for state in &delta.changed {
+        writer.write_signed_varint(state.entity_id);
+
+        writer.write_bool(state.position.is_some());
+        if let Some((x, y)) = state.position {
+            writer.write_bits(config.position.quantize(x), position_bits);
+            writer.write_bits(config.position.quantize(y), position_bits);
+        }
+
+        writer.write_bool(state.colour.is_some());
+        if let Some(colour) = state.colour {
+            writer.write_bits(encode_colour(colour), 2);
+        }
+    }
+
+    writer.write_varint(delta.removed.len() as u32);
+    for entity_id in &delta.removed {
+        writer.write_signed_varint(*entity_id);
+    }
+}
+
+fn decode_delta(reader: &mut BitReader, config: &WireConfig) -> Result<Delta, DecodeError> {
+    let position_bits = config.position.bits();
+
+    let baseline_tick = reader.read_signed_varint()?;
+
+    let count = reader.read_varint()?;
+    let mut changed = Vec::with_capacity((count as usize).min(reader.remaining_bytes()));
+    for _ in 0..count {
+        let entity_id = reader.read_signed_varint()?;
+
+        let position = if reader.read_bool()? {
+            let x = config.position.dequantize(reader.read_bits(position_bits)?);
+            let y = config.position.dequantize(reader.read_bits(position_bits)?);
+            Some((x, y))
+        } else {
+            None
+        };
+
+        let colour = if reader.read_bool()? {
+            Some(decode_colour(reader.read_bits(2)?)?)
+        } else {
+            None
+        };
+
+        changed.push(StateDelta {
+            entity_id,
+            position,
+            colour,
+        });
+    }
+
+    let count = reader.read_varint()?;
+    let mut removed = Vec::with_capacity((count as usize).min(reader.remaining_bytes()));
+    for _ in 0..count {
+        removed.push(reader.read_signed_varint()?);
+    }
+
+    Ok(Delta {
+        baseline_tick,
+        changed,
+        removed,
+    })
+}
+
+fn encode_channel(channel: Channel) -> u32 {
+    match channel {
+        Channel::Unreliable => 0,
+        Channel::ReliableUnordered => 1,
+        Channel::ReliableOrdered => 2,
+    }
+}
+
+fn decode_channel(channel: u32) -> Result<Channel, DecodeError> {
+    match channel {
+        0 => Ok(Channel::Unreliable),
+        1 => Ok(Channel::ReliableUnordered),
+        2 => Ok(Channel::ReliableOrdered),
+        _ => Err(DecodeError::InvalidChannel(channel as u8)),
+    }
+}
+
+fn encode_colour(colour: Colour) -> u32 {
+    match colour {
+        Colour::Red => 0,
+        Colour::Green => 1,
+        Colour::Blue => 2,
+    }
+}
+
+fn decode_colour(colour: u32) -> Result<Colour, DecodeError> {
+    match colour {
+        0 => Ok(Colour::Red),
+        1 => Ok(Colour::Green),
+        2 => Ok(Colour::Blue),
+        _ => Err(DecodeError::InvalidColour(colour as u8)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SimRng;
+
+    // A position that survives quantization unchanged, so whole messages can be compared
+    fn point(x: f32, y: f32) -> (f32, f32) {
+        let quantization = WireConfig::default().position;
+        let snap = |value| quantization.dequantize(quantization.quantize(value));
+        (snap(x), snap(y))
+    }
+
+    fn round_trip(message: &Message) -> Message {
+        let bytes = encode_message(message);
+        decode_message(&bytes).unwrap_or_else(|error| panic!("{} decoding {:?}", error, message))
+    }
+
+    fn assert_round_trips(message: Message) {
+        assert_eq!(round_trip(&message), message);
+    }
+
+    fn input(left: bool, right: bool, up: bool, down: bool, fire: bool) -> Input {
+        Input {
+            left,
+            right,
+            up,
+            down,
+            fire,
+        }
+    }
+
+    // Every optional field filled in at once
+    fn full_message() -> Message {
+        Message {
+            sequence: 1000,
+            state: Some(vec![
+                State {
+                    entity_id: 0,
+                    position: point(12.34, 56.78),
+                    colour: Colour::Red,
+                },
+                State {
+                    entity_id: 7,
+                    position: point(-100.0, 4000.0),
+                    colour: Colour::Blue,
+                },
+            ]),
+            input: Some(input(true, false, true, false, true)),
+            snapshot_tick: Some(998),
+            delta: Some(Delta {
+                baseline_tick: 990,
+                changed: vec![StateDelta {
+                    entity_id: 3,
+                    position: Some(point(1.0, 2.0)),
+                    colour: Some(Colour::Green),
+                }],
+                removed: vec![4, 5],
+            }),
+            snapshot_ack: Some(995),
+            packet: Some(PacketHeader {
+                sequence: 65535,
+                ack: Some(12),
+                ack_bits: 0xdead_beef,
+            }),
+            channel: Channel::ReliableOrdered,
+            channel_sequence: Some(40000),
+            ping: Some(u32::MAX),
+            pong: Some(Pong {
+                ping_time: 123456,
+                server_tick: -3,
+                tick_interval_ms: 16,
+            }),
+            input_lead: Some(-2),
+            previous_inputs: vec![
+                (999, input(true, false, true, false, true)),
+                (990, input(false, false, false, true, false)),
+            ],
+            view_tick: Some(991.25),
+            events: vec![CombatEvent::Kill { shooter: 1, target: 2 }],
+            despawns: vec![9],
+            handshake: Some(Handshake::Disconnect),
+        }
+    }
+
+    #[test]
+    fn empty_message_round_trips() {
+        assert_round_trips(Message::default());
+        assert_round_trips(Message {
+            sequence: -1,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn every_field_round_trips() {
+        assert_round_trips(full_message());
+    }
+
+    #[test]
+    fn each_optional_field_round_trips_on_its_own() {
+        let full = full_message();
+        let only = |fill: &dyn Fn(&mut Message)| {
+            let mut message = Message {
+                sequence: full.sequence,
+                ..Default::default()
+            };
+            fill(&mut message);
+            message
+        };
+
+        assert_round_trips(only(&|m| m.state = full.state.clone()));
+        assert_round_trips(only(&|m| m.state = Some(Vec::new())));
+        assert_round_trips(only(&|m| m.input = full.input));
+        assert_round_trips(only(&|m| m.snapshot_tick = full.snapshot_tick));
+        assert_round_trips(only(&|m| m.delta = full.delta.clone()));
+        assert_round_trips(only(&|m| m.snapshot_ack = full.snapshot_ack));
+        assert_round_trips(only(&|m| m.packet = full.packet));
+        assert_round_trips(only(&|m| {
+            m.packet = Some(PacketHeader {
+                sequence: 1,
+                ack: None,
+                ack_bits: 0,
+            })
+        }));
+        assert_round_trips(only(&|m| m.ping = full.ping));
+        assert_round_trips(only(&|m| m.pong = full.pong));
+        assert_round_trips(only(&|m| m.input_lead = full.input_lead));
+        assert_round_trips(only(&|m| m.view_tick = full.view_tick));
+        assert_round_trips(only(&|m| m.events = full.events.clone()));
+        assert_round_trips(only(&|m| m.despawns = full.despawns.clone()));
+        assert_round_trips(only(&|m| m.handshake = full.handshake));
+
+        // Previous inputs are written relative to the input ahead of them, with or without one
+        assert_round_trips(only(&|m| m.previous_inputs = full.previous_inputs.clone()));
+        assert_round_trips(only(&|m| {
+            m.input = full.input;
+            m.previous_inputs = full.previous_inputs.clone();
+        }));
+    }
+
+    #[test]
+    fn every_colour_round_trips() {
+        for colour in [Colour::Red, Colour::Green, Colour::Blue] {
+            assert_round_trips(Message {
+                state: Some(vec![State {
+                    entity_id: 1,
+                    position: point(10.0, 20.0),
+                    colour,
+                }]),
+                delta: Some(Delta {
+                    baseline_tick: 1,
+                    changed: vec![StateDelta {
+                        entity_id: 1,
+                        position: None,
+                        colour: Some(colour),
+                    }],
+                    removed: Vec::new(),
+                }),
+                handshake: Some(Handshake::ConnectRequest {
+                    colour,
+                    session: None,
+                    nonce: 0,
+                }),
+                ..Default::default()
+            });
+        }
+    }
+
+    #[test]
+    fn every_channel_round_trips() {
+        for channel in [Channel::Unreliable, Channel::ReliableUnordered, Channel::ReliableOrdered] {
+            assert_round_trips(Message {
+                channel,
+                channel_sequence: Some(7),
+                ..Default::default()
+            });
+        }
+
+        // Without a channel sequence the channel isn't written and reads back as unreliable
+        let message = round_trip(&Message {
+            channel: Channel::ReliableOrdered,
+            ..Default::default()
+        });
+        assert_eq!(message.channel, Channel::Unreliable);
+    }
+
+    #[test]
+    fn every_handshake_round_trips() {
+        let handshakes = [
+            Handshake::ConnectRequest {
+                colour: Colour::Green,
+                session: None,
+                nonce: 1,
+            },
+            Handshake::ConnectRequest {
+                colour: Colour::Blue,
+                session: Some(0xfeed_f00d),
+                nonce: u32::MAX,
+            },
+            Handshake::Challenge {
+                salt: 0x1234_5678,
+                nonce: 0x9abc_def0,
+            },
+            Handshake::ChallengeResponse { salt: u32::MAX },
+            Handshake::Accept {
+                entity_id: 42,
+                session: 1,
+            },
+            Handshake::Deny {
+                reason: DenyReason::ServerFull,
+            },
+            Handshake::Deny {
+                reason: DenyReason::ChallengeExpired,
+            },
+            Handshake::Disconnect,
+        ];
+
+        for handshake in handshakes {
+            assert_round_trips(Message {
+                handshake: Some(handshake),
+                ..Default::default()
+            });
+        }
+    }
+
+    #[test]
+    fn every_combat_event_round_trips() {
+        let events = vec![
+            CombatEvent::Shot {
+                shooter: 1,
+                from: point(10.5, 20.25),
+                to: point(600.0, -20.0),
+            },
+            CombatEvent::ProjectileFired {
+                projectile_id: 3,
+                projectile: Projectile {
+                    owner: 1,
+                    position: point(100.0, 100.0),
+                    velocity: point(-15.0, 0.0),
+                    ticks_left: 40,
+                },
+            },
+            CombatEvent::Hit {
+                shooter: 1,
+                target: 2,
+                projectile: None,
+                health: 75,
+            },
+            CombatEvent::Hit {
+                shooter: 1,
+                target: 2,
+                projectile: Some(3),
+                health: -25,
+            },
+            CombatEvent::Kill { shooter: 1, target: 2 },
+        ];
+
+        assert_round_trips(Message {
+            events,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = encode_message(&full_message());
+        bytes[0] = WIRE_VERSION.wrapping_add(1);
+        assert_eq!(
+            decode_message(&bytes),
+            Err(DecodeError::UnsupportedVersion(WIRE_VERSION.wrapping_add(1)))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let bytes = encode_message(&full_message());
+        for length in 0..bytes.len() {
+            assert_eq!(
+                decode_message(&bytes[..length]),
+                Err(DecodeError::UnexpectedEnd),
+                "truncated to {} bytes",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = encode_message(&full_message());
+        bytes.extend_from_slice(&[0, 0]);
+        assert_eq!(decode_message(&bytes), Err(DecodeError::TrailingBytes(2)));
+    }
+
+    #[test]
+    fn rejects_varints_over_32_bits() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(WIRE_VERSION as u32, 8);
+        writer.write_bits(0, TAG_BITS);
+        // Sequence with every group full and the continuation bit always set
+        for _ in 0..6 {
+            writer.write_bits(0x7f, 7);
+            writer.write_bool(true);
+        }
+
+        assert_eq!(decode_message(&writer.finish()), Err(DecodeError::InvalidVarint));
+    }
+
+    #[test]
+    fn rejects_out_of_range_enums() {
+        let decode_after = |write: &dyn Fn(&mut BitWriter), tags| {
+            let mut writer = BitWriter::new();
+            writer.write_bits(WIRE_VERSION as u32, 8);
+            writer.write_bits(tags, TAG_BITS);
+            writer.write_signed_varint(0);
+            write(&mut writer);
+            decode_message(&writer.finish())
+        };
+
+        // One state with colour 3
+        let state = |writer: &mut BitWriter| {
+            writer.write_varint(1);
+            writer.write_signed_varint(0);
+            let position_bits = WireConfig::default().position.bits();
+            writer.write_bits(0, position_bits);
+            writer.write_bits(0, position_bits);
+            writer.write_bits(3, 2);
+        };
+        assert_eq!(decode_after(&state, TAG_STATE), Err(DecodeError::InvalidColour(3)));
+
+        let channel = |writer: &mut BitWriter| writer.write_bits(3, 2);
+        assert_eq!(decode_after(&channel, TAG_CHANNEL), Err(DecodeError::InvalidChannel(3)));
+
+        let handshake = |writer: &mut BitWriter| writer.write_bits(7, 3);
+        assert_eq!(decode_after(&handshake, TAG_HANDSHAKE), Err(DecodeError::InvalidHandshake(7)));
+
+        let deny = |writer: &mut BitWriter| {
+            writer.write_bits(4, 3);
+            writer.write_bits(3, 2);
+        };
+        assert_eq!(decode_after(&deny, TAG_HANDSHAKE), Err(DecodeError::InvalidDenyReason(3)));
+    }
+
+    #[test]
+    fn garbage_is_an_error_not_a_panic() {
+        let mut rng = SimRng::new(3);
+        for length in 0..2000 {
+            let mut bytes: Vec<u8> = (0..length % 64).map(|_| rng.next_u64() as u8).collect();
+            // Most of the time get past the version check so the rest gets read
+            if !bytes.is_empty() && rng.chance(0.9) {
+                bytes[0] = WIRE_VERSION;
+            }
+            let _ = decode_message(&bytes);
+        }
+    }
+
+    // The demo world, two players and an npc part way round its circle
+    fn demo_world() -> Vec<State> {
+        vec![
+            State {
+                entity_id: 0,
+                position: (104.83, 98.71),
+                colour: Colour::Blue,
+            },
+            State {
+                entity_id: 1,
+                position: (215.0, 340.0),
+                colour: Colour::Red,
+            },
+            State {
+                entity_id: 2,
+                position: (480.0, 125.0),
+                colour: Colour::Green,
+            },
+        ]
+    }
+
+    // The same snapshot with every field in whole bytes, the way it went before
+    // bit packing: version, 16 bits of tags, then 32 bit sequence and tick, a 16 bit state
+    // count, and for each state a 32 bit entity id, two f32s and a byte of colour
+    fn encode_byte_aligned(message: &Message) -> Vec<u8> {
+        let mut bytes = vec![WIRE_VERSION];
+        bytes.extend_from_slice(&((TAG_STATE | TAG_SNAPSHOT_TICK) as u16).to_be_bytes());
+        bytes.extend_from_slice(&message.sequence.to_be_bytes());
+        bytes.extend_from_slice(&message.snapshot_tick.unwrap_or_default().to_be_bytes());
+
+        let states = message.state.as_deref().unwrap_or_default();
+        bytes.extend_from_slice(&(states.len() as u16).to_be_bytes());
+        for state in states {
+            bytes.extend_from_slice(&state.entity_id.to_be_bytes());
+            bytes.extend_from_slice(&state.position.0.to_be_bytes());
+            bytes.extend_from_slice(&state.position.1.to_be_bytes());
+            bytes.push(encode_colour(state.colour) as u8);
+        }
+        bytes
+    }
+
+    #[test]
+    fn demo_snapshot_sizes() {
+        let world = demo_world();
+        let tick = 1200;
+
+        // What a client without a baseline gets
+        let full = Message {
+            sequence: tick - 3,
+            state: Some(world.clone()),
+            snapshot_tick: Some(tick),
+            ..Default::default()
+        };
+
+        // Against the last tick, where only the npc has moved
+        let baseline = world.iter().map(|state| (state.entity_id, *state)).collect();
+        let mut moved = world.clone();
+        moved[0].position = (105.12, 103.7);
+        let delta = Message {
+            sequence: tick - 2,
+            delta: Some(Delta::between(tick, &baseline, &moved)),
+            snapshot_tick: Some(tick + 1),
+            ..Default::default()
+        };
+
+        let byte_aligned_size = encode_byte_aligned(&full).len();
+        let full_size = encode_message(&full).len();
+        let delta_size = encode_message(&delta).len();
+
+        assert!(full_size < byte_aligned_size);
+        assert!(delta_size < full_size);
+    }
+}